@@ -0,0 +1,240 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::mem::phys_to_virt;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Longer tables are taken to be corrupt rather than summed up
+const MAX_TABLE_LENGTH: usize = 1 << 20;
+
+/// Root System Description Pointer (ACPI 2.0 layout, the
+/// fields after `rsdt_address` only exist when `revision >= 2`)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by every system description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// A validated system description table in physical memory
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub header: SdtHeader,
+    pub phys: PhysAddr,
+}
+
+impl Table {
+    /// The bytes following the header
+    pub fn body(&self) -> &'static [u8] {
+        let start = phys_to_virt(self.phys + size_of::<SdtHeader>() as u64);
+        let len = (self.header.length as usize).saturating_sub(size_of::<SdtHeader>());
+        unsafe { core::slice::from_raw_parts(start.as_ptr(), len) }
+    }
+}
+
+/// Physical addresses of all tables listed by the RSDT/XSDT
+static TABLES: Once<Vec<PhysAddr>> = Once::new();
+
+fn checksum_ok(phys: PhysAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(phys).as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Scan `[start, end)` on 16 byte boundaries for a valid RSDP
+fn scan_rsdp(start: u64, end: u64) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|addr| {
+        let phys = PhysAddr::new(addr);
+        let rsdp = unsafe { read_unaligned(phys_to_virt(phys).as_ptr::<Rsdp>()) };
+
+        // the first 20 bytes are the ACPI 1.0 structure and checksummed on their own
+        (&rsdp.signature == RSDP_SIGNATURE && checksum_ok(phys, 20)).then_some(rsdp)
+    })
+}
+
+/// Find the RSDP in the first KiB of the EBDA or the BIOS area
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment =
+        unsafe { read_unaligned(phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>()) };
+    let ebda = (ebda_segment as u64) << 4;
+
+    let in_ebda = if ebda != 0 {
+        scan_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| scan_rsdp(0xE0000, 0x100000))
+}
+
+fn read_header(phys: PhysAddr) -> SdtHeader {
+    unsafe { read_unaligned(phys_to_virt(phys).as_ptr::<SdtHeader>()) }
+}
+
+/// Whether the table at `phys` has a sane length and checksum
+fn table_ok(phys: PhysAddr, header: &SdtHeader) -> bool {
+    let len = header.length as usize;
+    (size_of::<SdtHeader>()..=MAX_TABLE_LENGTH).contains(&len) && checksum_ok(phys, len)
+}
+
+/// Locate the RSDP and collect the tables of the RSDT or XSDT
+///
+/// Returns the number of tables found, 0 when there is no ACPI.
+pub fn init() -> usize {
+    TABLES
+        .call_once(|| {
+            let Some(rsdp) = find_rsdp() else {
+                return Vec::new();
+            };
+
+            // the XSDT holds 64 bit entries, the RSDT 32 bit ones
+            let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+                (PhysAddr::new(rsdp.xsdt_address), 8)
+            } else {
+                (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+            };
+
+            let header = read_header(root);
+            if !table_ok(root, &header) {
+                return Vec::new();
+            }
+
+            let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+            let first = phys_to_virt(root + size_of::<SdtHeader>() as u64);
+            (0..entries)
+                .map(|i| unsafe {
+                    let entry = first + (i * entry_size) as u64;
+                    match entry_size {
+                        8 => PhysAddr::new(read_unaligned(entry.as_ptr::<u64>())),
+                        _ => PhysAddr::new(read_unaligned(entry.as_ptr::<u32>()) as u64),
+                    }
+                })
+                .collect()
+        })
+        .len()
+}
+
+/// Find the table with the given signature, e.g. `b"APIC"` for the MADT
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    TABLES.r#try()?.iter().find_map(|&phys| {
+        let header = read_header(phys);
+        (&header.signature == signature && table_ok(phys, &header))
+            .then_some(Table { header, phys })
+    })
+}
+
+/// The CMOS register holding the century, from the FADT
+///
+/// `None` when there is no FADT or it does not name one.
+pub fn century_register() -> Option<u8> {
+    // the century field is at byte 108 of the FADT, right after the header
+    let body = find_table(b"FACP")?.body();
+    let register = *body.get(108 - size_of::<SdtHeader>())?;
+    (register != 0).then_some(register)
+}
+
+/// Interrupt source override from the MADT: ISA `irq` is wired to `gsi`
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub flags: u16, // MPS INTI flags, polarity in bits 0-1 and trigger mode in bits 2-3
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// The parts of the Multiple APIC Description Table the kernel uses
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_8259: bool,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+fn le_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+impl Madt {
+    pub fn parse() -> Option<Madt> {
+        let body = find_table(b"APIC")?.body();
+
+        let mut madt = Madt {
+            local_apic_address: le_u32(body, 0)? as u64,
+            has_8259: le_u32(body, 4)? & 1 != 0,
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        // variable length entries follow: type, length, data
+        let mut entries = body.get(8..)?;
+        while let [kind, len, ..] = *entries {
+            let len = len as usize;
+            let Some(entry) = entries.get(..len).filter(|_| len >= 2) else {
+                break;
+            };
+            // entries too short for their type are skipped
+            madt.add_entry(kind, entry);
+            entries = &entries[len..];
+        }
+
+        Some(madt)
+    }
+
+    /// Record one MADT entry, `None` when it is malformed
+    fn add_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            1 => self.io_apics.push(IoApicEntry {
+                id: *entry.get(2)?,
+                address: le_u32(entry, 4)?,
+                gsi_base: le_u32(entry, 8)?,
+            }),
+            2 => self.overrides.push(InterruptOverride {
+                irq: *entry.get(3)?,
+                gsi: le_u32(entry, 4)?,
+                flags: le_u16(entry, 8)?,
+            }),
+            5 => self.local_apic_address = le_u64(entry, 4)?,
+            _ => {}
+        }
+        Some(())
+    }
+}
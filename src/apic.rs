@@ -0,0 +1,372 @@
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::{InterruptOverride, Madt};
+use crate::clock::{self, Instant};
+use crate::interrupts::PICS;
+use crate::irq;
+use crate::{cpu, mem, println};
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+pub const TIMER_VECTOR: u8 = 0xF0;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+// local APIC register offsets
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SPURIOUS: u64 = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3E0;
+
+// local vector table timer bits
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+// I/O APIC registers, accessed through the select/window pair
+const IOAPIC_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+// redirection entry bits
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, reg: u64) -> u32 {
+        unsafe { read_volatile((self.base + reg).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: u64, value: u32) {
+        unsafe { write_volatile((self.base + reg).as_mut_ptr::<u32>(), value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), reg);
+            read_volatile((self.base + IOAPIC_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            write_volatile((self.base + IOAPIC_SELECT).as_mut_ptr::<u32>(), reg);
+            write_volatile((self.base + IOAPIC_WINDOW).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn read_redirection(&mut self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_redirection(&mut self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+        // write the masked low half first so a half updated entry never fires
+        self.write(reg, entry as u32 | REDIRECT_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+struct Apic {
+    local: LocalApic,
+    io_apics: Mutex<Vec<IoApic>>,
+    overrides: Vec<InterruptOverride>,
+}
+
+static APIC: Once<Apic> = Once::new();
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// APIC timer counts per ms in one-shot mode, 0 for TSC-deadline mode
+static TIMER_KHZ: AtomicU64 = AtomicU64::new(0);
+static TIMER_READY: AtomicBool = AtomicBool::new(false);
+
+/// Whether interrupts are delivered through the APICs (true)
+/// or the legacy 8259 PICs (false)
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    APIC.r#try().map(|apic| &apic.local)
+}
+
+/// Acknowledge the interrupt being serviced
+pub fn end_of_interrupt() {
+    if let Some(local) = local_apic() {
+        local.end_of_interrupt();
+    }
+}
+
+impl Apic {
+    /// The GSI and redirection flags an ISA IRQ is wired to,
+    /// ISA IRQs are identity mapped unless the MADT overrides them
+    fn isa_route(&self, irq: u8) -> (u32, u64) {
+        let Some(over) = self.overrides.iter().find(|o| o.irq == irq) else {
+            return (irq as u32, 0);
+        };
+
+        let mut flags = 0;
+        if over.flags & 0b11 == 0b11 {
+            flags |= REDIRECT_ACTIVE_LOW;
+        }
+        if (over.flags >> 2) & 0b11 == 0b11 {
+            flags |= REDIRECT_LEVEL;
+        }
+        (over.gsi, flags)
+    }
+
+    fn with_io_apic<R>(&self, gsi: u32, f: impl FnOnce(&mut IoApic) -> R) -> Option<R> {
+        without_interrupts(|| {
+            let mut io_apics = self.io_apics.lock();
+            io_apics.iter_mut().find(|io| io.handles(gsi)).map(f)
+        })
+    }
+}
+
+/// Route ISA `irq` to `vector` on this CPU
+pub fn route_isa_irq(irq: u8, vector: u8, masked: bool) {
+    let Some(apic) = APIC.r#try() else {
+        return;
+    };
+
+    let (gsi, flags) = apic.isa_route(irq);
+    let mut entry = vector as u64 | flags | (apic.local.id() as u64) << 56;
+    if masked {
+        entry |= REDIRECT_MASKED;
+    }
+    apic.with_io_apic(gsi, |io| io.write_redirection(gsi, entry));
+}
+
+/// The I/O APIC input ISA `irq` is wired to, `None` without APICs
+pub fn isa_gsi(irq: u8) -> Option<u32> {
+    let apic = APIC.r#try()?;
+    Some(apic.isa_route(irq).0)
+}
+
+/// Mask or unmask ISA `irq` at its I/O APIC
+pub fn set_isa_irq_masked(irq: u8, masked: bool) {
+    let Some(apic) = APIC.r#try() else {
+        return;
+    };
+
+    let (gsi, _) = apic.isa_route(irq);
+    apic.with_io_apic(gsi, |io| {
+        let entry = io.read_redirection(gsi) & !REDIRECT_MASKED;
+        let masked = if masked { REDIRECT_MASKED } else { 0 };
+        io.write_redirection(gsi, entry | masked);
+    });
+}
+
+/// Route `gsi`, an I/O APIC input above the ISA range, to `vector`
+/// on this CPU
+///
+/// These inputs are wired like PCI interrupts, level triggered and
+/// active low. Returns false when no I/O APIC has the input.
+pub fn route_gsi(gsi: u32, vector: u8) -> bool {
+    let Some(apic) = APIC.r#try() else {
+        return false;
+    };
+
+    let entry =
+        vector as u64 | REDIRECT_LEVEL | REDIRECT_ACTIVE_LOW | (apic.local.id() as u64) << 56;
+    apic.with_io_apic(gsi, |io| io.write_redirection(gsi, entry))
+        .is_some()
+}
+
+/// Mask an input routed with `route_gsi`
+pub fn mask_gsi(gsi: u32) {
+    if let Some(apic) = APIC.r#try() {
+        apic.with_io_apic(gsi, |io| io.write_redirection(gsi, REDIRECT_MASKED));
+    }
+}
+
+/// Switch interrupt delivery from the 8259 PICs to the APICs
+///
+/// Needs an APIC in CPUID and a MADT describing at least one
+/// I/O APIC, otherwise the PICs stay in charge and this returns false.
+pub fn init() -> bool {
+    if !cpu::has_apic() {
+        return false;
+    }
+    let Some(madt) = Madt::parse() else {
+        return false;
+    };
+    if madt.io_apics.is_empty() {
+        return false;
+    }
+
+    let local_base = mem::map_mmio(PhysAddr::new(madt.local_apic_address), 0x1000)
+        .expect("failed to map local APIC");
+
+    let mut io_apics = Vec::new();
+    for entry in &madt.io_apics {
+        let base = mem::map_mmio(PhysAddr::new(entry.address as u64), 0x20)
+            .expect("failed to map I/O APIC");
+        let mut io = IoApic {
+            base,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        io.entries = ((io.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+
+        // start with everything masked, routes are added explicitly
+        for gsi in io.gsi_base..io.gsi_base + io.entries {
+            io.write_redirection(gsi, REDIRECT_MASKED);
+        }
+        io_apics.push(io);
+    }
+
+    without_interrupts(|| {
+        let apic = APIC.call_once(|| Apic {
+            local: LocalApic { base: local_base },
+            io_apics: Mutex::new(io_apics),
+            overrides: madt.overrides,
+        });
+
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            base.write(base.read() | APIC_BASE_ENABLE);
+
+            // the 8259s stay remapped so a stray interrupt lands on a known vector
+            PICS.lock().disable();
+        }
+        apic.local.write(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+
+        // the cascade line has no device, its GSI usually belongs to the timer
+        for line in (0..irq::LINES).filter(|&line| line != irq::CASCADE) {
+            route_isa_irq(line, irq::vector(line), irq::is_masked(line));
+        }
+
+        ACTIVE.store(true, Ordering::Release);
+    });
+
+    println!(
+        "APIC: local apic {}, {} I/O APIC(s)",
+        local_apic().map_or(0, |l| l.id()),
+        madt.io_apics.len()
+    );
+    true
+}
+
+/// Set up the local APIC timer for one-shot wakeups on `TIMER_VECTOR`
+///
+/// Uses TSC-deadline mode when the CPU has it, otherwise measures
+/// the timer against the monotonic clock, which must be calibrated.
+pub fn init_timer() -> bool {
+    let Some(local) = local_apic() else {
+        return false;
+    };
+    if !is_active() || clock::tsc_khz() == 0 {
+        return false;
+    }
+
+    if cpu::has_tsc_deadline() {
+        local.write(LAPIC_LVT_TIMER, LVT_TSC_DEADLINE | TIMER_VECTOR as u32);
+        println!("APIC timer: TSC-deadline mode");
+    } else {
+        local.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        local.write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        local.write(LAPIC_TIMER_INITIAL, u32::MAX);
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(10) {}
+        let counted = u32::MAX - local.read(LAPIC_TIMER_CURRENT);
+        local.write(LAPIC_TIMER_INITIAL, 0);
+
+        let khz = (counted as u64 / 10).max(1);
+        TIMER_KHZ.store(khz, Ordering::Relaxed);
+        local.write(LAPIC_LVT_TIMER, TIMER_VECTOR as u32);
+        println!("APIC timer: one-shot mode, {} kHz", khz);
+    }
+
+    TIMER_READY.store(true, Ordering::Release);
+    true
+}
+
+/// Whether `arm_timer` can be used
+pub fn timer_ready() -> bool {
+    TIMER_READY.load(Ordering::Acquire)
+}
+
+/// Longest delay `arm_timer` can wait, longer ones are cut short
+pub fn max_timer_delay() -> Duration {
+    match TIMER_KHZ.load(Ordering::Relaxed) {
+        0 => Duration::MAX, // the 64 bit TSC deadline does not run out
+        khz => Duration::from_nanos(u32::MAX as u64 * 1_000_000 / khz),
+    }
+}
+
+/// Raise `TIMER_VECTOR` once `after` has passed
+pub fn arm_timer(after: Duration) {
+    let Some(local) = local_apic() else {
+        return;
+    };
+    let nanos = after.as_nanos().min(u64::MAX as u128) as u64;
+
+    match TIMER_KHZ.load(Ordering::Relaxed) {
+        0 => {
+            let cycles = (nanos as u128 * clock::tsc_khz() as u128 / 1_000_000) as u64;
+            let deadline = cpu::rdtsc().saturating_add(cycles.max(1));
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+        }
+        khz => {
+            let count = (nanos as u128 * khz as u128 / 1_000_000).clamp(1, u32::MAX as u128);
+            local.write(LAPIC_TIMER_INITIAL, count as u32);
+        }
+    }
+}
+
+/// Cancel a wakeup set with `arm_timer`
+pub fn stop_timer() {
+    let Some(local) = local_apic() else {
+        return;
+    };
+
+    match TIMER_KHZ.load(Ordering::Relaxed) {
+        0 => unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) },
+        _ => local.write(LAPIC_TIMER_INITIAL, 0),
+    }
+}
@@ -0,0 +1,190 @@
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::pit::{self, PIT};
+use crate::{cpu, hpet, println, timer};
+
+// calibration runs for 10 ms of PIT channel 2 and keeps the best of a few
+const CALIBRATE_COUNT: u16 = (pit::BASE_FREQUENCY / 100) as u16;
+const CALIBRATE_RUNS: usize = 5;
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+// ns = (tsc - BASE_TSC) * MULT >> 32, MULT is 0 until calibrated
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static MULT: AtomicU64 = AtomicU64::new(0);
+
+// largest value handed out so far, keeps the clock from stepping back
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Measure the TSC frequency against the HPET or PIT and start the clock
+///
+/// Until this runs the clock falls back to the timer tick.
+pub fn init() {
+    let khz = calibrate_hpet().unwrap_or_else(calibrate_pit);
+    let invariant = cpu::has_invariant_tsc();
+
+    without_interrupts(|| {
+        // continue from the tick based time instead of restarting at 0
+        let offset = nanos();
+        let mult = (1_000_000u64 << 32) / khz;
+        let base = cpu::rdtsc().wrapping_sub(offset.saturating_mul(khz) / 1_000_000);
+
+        TSC_KHZ.store(khz, Ordering::Relaxed);
+        INVARIANT.store(invariant, Ordering::Relaxed);
+        BASE_TSC.store(base, Ordering::Relaxed);
+        MULT.store(mult, Ordering::Release);
+    });
+
+    println!(
+        "TSC: {}.{:03} MHz, invariant: {}",
+        khz / 1000,
+        khz % 1000,
+        invariant
+    );
+}
+
+/// TSC cycles per millisecond, 0 before calibration
+pub fn tsc_khz() -> u64 {
+    TSC_KHZ.load(Ordering::Relaxed)
+}
+
+/// Whether the TSC rate is independent of power states, without
+/// this the clock can drift when the CPU changes frequency
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// TSC cycles in 10 ms of the HPET main counter, converted to kHz
+fn calibrate_hpet() -> Option<u64> {
+    let frequency = hpet::frequency()?;
+    let wait = frequency / 100;
+
+    let mut best = (u64::MAX, 1);
+    for _ in 0..CALIBRATE_RUNS {
+        let (cycles, counts) = without_interrupts(|| {
+            let start = hpet::counter()?;
+            let start_tsc = cpu::rdtsc();
+            let mut now = start;
+            while now - start < wait {
+                now = hpet::counter()?;
+            }
+            Some((cpu::rdtsc() - start_tsc, now - start))
+        })?;
+
+        // compare runs by cycles per count, without dividing
+        if (cycles as u128) * (best.1 as u128) < (best.0 as u128) * (counts as u128) {
+            best = (cycles, counts);
+        }
+    }
+
+    Some((best.0 as u128 * frequency as u128 / best.1 as u128 / 1000).max(1) as u64)
+}
+
+/// TSC cycles in 10 ms of PIT channel 2, converted to kHz
+///
+/// The shortest run wins since anything interrupting the
+/// measurement only makes it longer.
+fn calibrate_pit() -> u64 {
+    let mut best = u64::MAX;
+    for _ in 0..CALIBRATE_RUNS {
+        let cycles = without_interrupts(|| {
+            let mut pit = PIT.lock();
+            pit.start_channel2(CALIBRATE_COUNT);
+            let start = cpu::rdtsc();
+            while !pit.channel2_done() {}
+            cpu::rdtsc() - start
+        });
+        best = best.min(cycles);
+    }
+
+    (best * pit::BASE_FREQUENCY as u64 / CALIBRATE_COUNT as u64 / 1000).max(1)
+}
+
+/// Nanoseconds since boot
+fn nanos() -> u64 {
+    let mult = MULT.load(Ordering::Acquire);
+    let now = if mult == 0 {
+        timer::uptime().as_nanos() as u64
+    } else {
+        let cycles = cpu::rdtsc().wrapping_sub(BASE_TSC.load(Ordering::Relaxed));
+        ((cycles as u128 * mult as u128) >> 32) as u64
+    };
+
+    LAST_NS.fetch_max(now, Ordering::Relaxed).max(now)
+}
+
+/// A point on the monotonic clock, with nanosecond resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(nanos())
+    }
+
+    /// Time since boot at this instant
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
@@ -0,0 +1,105 @@
+use alloc::string::String;
+use alloc::vec;
+use spin::Once;
+use x86_64::instructions::port::Port;
+
+// QEMU firmware configuration device
+const FW_CFG_SELECTOR: u16 = 0x510;
+const FW_CFG_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+/// fw_cfg file holding the command line, passed to QEMU with
+/// `-fw_cfg name=opt/os/cmdline,string="tz=Europe/Berlin rtc=local"`
+const FW_CFG_CMDLINE: &[u8] = b"opt/os/cmdline";
+
+/// Used when the firmware passes no command line
+const BUILTIN: Option<&str> = option_env!("KERNEL_CMDLINE");
+
+static CMDLINE: Once<String> = Once::new();
+
+/// Reads items from the QEMU fw_cfg device over port I/O
+struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfg {
+    /// `None` when not running under QEMU
+    fn open() -> Option<Self> {
+        let mut fw_cfg = Self {
+            selector: Port::new(FW_CFG_SELECTOR),
+            data: Port::new(FW_CFG_DATA),
+        };
+
+        fw_cfg.select(FW_CFG_SIGNATURE);
+        let mut signature = [0; 4];
+        fw_cfg.read(&mut signature);
+        (&signature == b"QEMU").then_some(fw_cfg)
+    }
+
+    fn select(&mut self, item: u16) {
+        unsafe { self.selector.write(item) }
+    }
+
+    /// Read the next bytes of the selected item
+    fn read(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    /// The selector and size of the file called `name`
+    fn find_file(&mut self, name: &[u8]) -> Option<(u16, usize)> {
+        self.select(FW_CFG_FILE_DIR);
+        let mut count = [0; 4];
+        self.read(&mut count);
+
+        // big endian entries: size u32, select u16, reserved u16, name [u8; 56]
+        for _ in 0..u32::from_be_bytes(count) {
+            let mut entry = [0; 64];
+            self.read(&mut entry);
+            let file_name = entry[8..].split(|&byte| byte == 0).next()?;
+            if file_name == name {
+                let size = u32::from_be_bytes(entry[0..4].try_into().unwrap());
+                let select = u16::from_be_bytes(entry[4..6].try_into().unwrap());
+                return Some((select, size as usize));
+            }
+        }
+        None
+    }
+}
+
+fn read_fw_cfg() -> Option<String> {
+    let mut fw_cfg = FwCfg::open()?;
+    let (select, size) = fw_cfg.find_file(FW_CFG_CMDLINE)?;
+
+    let mut bytes = vec![0; size];
+    fw_cfg.select(select);
+    fw_cfg.read(&mut bytes);
+
+    let text = String::from_utf8(bytes).ok()?;
+    Some(String::from(text.trim_end_matches('\0').trim()))
+}
+
+/// Load the kernel command line, from QEMU's fw_cfg or the
+/// `KERNEL_CMDLINE` build variable, needs the heap
+pub fn init() {
+    CMDLINE.call_once(|| read_fw_cfg().unwrap_or_else(|| String::from(BUILTIN.unwrap_or(""))));
+}
+
+/// The whole command line, empty before `init`
+pub fn as_str() -> &'static str {
+    CMDLINE.r#try().map_or("", String::as_str)
+}
+
+/// Value of the last `key=value` option, `Some("")` for a bare `key`
+pub fn get(key: &str) -> Option<&'static str> {
+    as_str()
+        .split_whitespace()
+        .filter_map(|option| match option.split_once('=') {
+            Some((name, value)) => (name == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+        .next_back()
+}
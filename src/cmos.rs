@@ -1,20 +1,43 @@
-use crate::time::DateTime;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
-const CMOS_ADDR: u16 = 0x70; // write only
+const CMOS_ADDR: u16 = 0x70; // write only, bit 7 disables NMIs
 const CMOS_DATA: u16 = 0x71; // read and write
 
-pub static mut CMOS_INSTANCE: CMOS = CMOS::init();
+const NMI_DISABLE: u8 = 0x80;
 
-#[derive(Debug)]
-enum CMOSRTCRegister {
-    Seconds = 0x00,    // 0-59
-    Minutes = 0x02,    // 0-59
-    Hours = 0x04,      // 24 hour clock, 0-23
-    Weekday = 0x06,    // 1-7
-    DayOfMonth = 0x07, // 1-31
-    Month = 0x08,      // 1-12
-    Year = 0x09,       // last 2 digits of the year (e.g 25 for 2025)
+// first byte past the RTC registers, the rest is BIOS NVRAM
+const NVRAM_START: u8 = 0x0E;
+// bit 7 of the index is the NMI disable bit, so only 128 bytes are addressable
+const NVRAM_END: u8 = 0x80;
+
+// BIOS NVRAM layout
+const NVRAM_FLOPPY_TYPES: u8 = 0x10; // high nibble drive 0, low nibble drive 1
+const NVRAM_DISK_TYPES: u8 = 0x12; // high nibble disk 0, low nibble disk 1
+
+static CMOS_INSTANCE: Mutex<CMOS> = Mutex::new(CMOS::init());
+
+/// Run `f` with exclusive access to the CMOS
+///
+/// Interrupts are disabled meanwhile so a handler can never
+/// select another register halfway through an access.
+pub fn with_cmos<R>(f: impl FnOnce(&mut CMOS) -> R) -> R {
+    without_interrupts(|| f(&mut CMOS_INSTANCE.lock()))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CMOSRTCRegister {
+    Seconds = 0x00,      // 0-59
+    SecondsAlarm = 0x01, // 0-59, 0xC0 and up matches any
+    Minutes = 0x02,      // 0-59
+    MinutesAlarm = 0x03, // 0-59, 0xC0 and up matches any
+    Hours = 0x04,        // 24 hour clock, 0-23
+    HoursAlarm = 0x05,   // same format as Hours, 0xC0 and up matches any
+    Weekday = 0x06,      // 1-7
+    DayOfMonth = 0x07,   // 1-31
+    Month = 0x08,        // 1-12
+    Year = 0x09,         // last 2 digits of the year (e.g 25 for 2025)
 
     StatusA = 0x0A,
     StatusB = 0x0B,
@@ -29,13 +52,32 @@ impl CMOSRTCRegister {
 }
 
 /// Convert binary-coded decimal to normal binary numbers
-pub(crate) fn bcd_to_binary(byte: u8) -> u8 {
+pub fn bcd_to_binary(byte: u8) -> u8 {
     (byte & 0x0F) + ((byte / 16) * 10)
 }
 
-pub(crate) struct CMOS {
+/// Convert a binary number below 100 to binary-coded decimal
+pub fn binary_to_bcd(byte: u8) -> u8 {
+    ((byte / 10) << 4) | (byte % 10)
+}
+
+/// Clock registers as stored in the CMOS, BCD or binary and
+/// 12 or 24 hour depending on StatusB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawTime {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8, // bit 7 is the PM flag in 12 hour mode
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    pub century: Option<u8>,
+}
+
+pub struct CMOS {
     address_register: Port<u8>,
     data_register: Port<u8>,
+    nmi_enabled: bool,
 }
 
 impl CMOS {
@@ -43,65 +85,157 @@ impl CMOS {
         Self {
             address_register: Port::new(CMOS_ADDR),
             data_register: Port::new(CMOS_DATA),
+            nmi_enabled: true,
         }
     }
 
-    /// This function is unsafe because the caller
-    /// needs to guarentee that the register they are
-    /// writing to has write permissions and exists.
-    /// If not, this can lead to undefined behavour.
-    pub(crate) unsafe fn write_register(&mut self, data: u8) {
-        self.address_register.write(data);
+    /// Select the byte at `index` with NMIs disabled, so an NMI
+    /// handler cannot interrupt between selecting and accessing it
+    fn select(&mut self, index: u8) {
+        unsafe { self.address_register.write(index | NMI_DISABLE) };
     }
 
-    /// Read from the CMOS data register
+    /// Put the NMI disable bit back the way it was asked for
     ///
-    /// This function is unsafe because .read can cause
-    /// side effects that are not memory safe
-    pub(crate) unsafe fn read_cmos(&mut self) -> u8 {
-        // write to cmos address register
-        self.data_register.read()
+    /// StatusD is read only, so leaving it selected is harmless.
+    fn deselect(&mut self) {
+        let nmi = if self.nmi_enabled { 0 } else { NMI_DISABLE };
+        unsafe {
+            self.address_register
+                .write(CMOSRTCRegister::StatusD.as_u8() | nmi)
+        };
     }
 
-    /// Check the StatusB register
-    /// Check bit 2, value 4, 1 = binary mode set
-    pub(crate) fn is_bcd_mode(&mut self) -> bool {
-        unsafe {
-            self.write_register(CMOSRTCRegister::StatusB.as_u8());
-            self.read_cmos() & 0x04 == 0
-        }
+    fn read_index(&mut self, index: u8) -> u8 {
+        self.select(index);
+        let value = unsafe { self.data_register.read() };
+        self.deselect();
+        value
     }
 
-    /// Check the status b register
-    /// Check bit 1, value = 2, 1 = hours are in 24 format
-    pub(crate) fn is_24_hour_format(&mut self) -> bool {
-        unsafe {
-            self.write_register(CMOSRTCRegister::StatusB.as_u8());
-            self.read_cmos() & 0x02 == 0
+    fn write_index(&mut self, index: u8, value: u8) {
+        self.select(index);
+        unsafe { self.data_register.write(value) };
+        self.deselect();
+    }
+
+    pub fn read(&mut self, register: CMOSRTCRegister) -> u8 {
+        self.read_index(register.as_u8())
+    }
+
+    pub fn write(&mut self, register: CMOSRTCRegister, value: u8) {
+        self.write_index(register.as_u8(), value);
+    }
+
+    /// Enable or disable non-maskable interrupts, which share the
+    /// address port with the CMOS
+    pub fn set_nmi_enabled(&mut self, enabled: bool) {
+        self.nmi_enabled = enabled;
+        self.deselect();
+    }
+
+    /// Read a byte of the BIOS NVRAM, `None` outside 0x0E-0x7F
+    pub fn read_nvram(&mut self, index: u8) -> Option<u8> {
+        (NVRAM_START..NVRAM_END)
+            .contains(&index)
+            .then(|| self.read_index(index))
+    }
+
+    /// Write a byte of the BIOS NVRAM, returns false outside 0x0E-0x7F
+    ///
+    /// The BIOS checksums parts of the NVRAM, changing them can make
+    /// it fall back to defaults on the next boot.
+    pub fn write_nvram(&mut self, index: u8, value: u8) -> bool {
+        let in_range = (NVRAM_START..NVRAM_END).contains(&index);
+        if in_range {
+            self.write_index(index, value);
         }
+        in_range
     }
 
-    pub(crate) unsafe fn time_now(&mut self) -> DateTime {
-        let mut time = DateTime::new(0, 0, 0, 0, 0, 0);
+    /// The BIOS floppy drive types, (drive 0, drive 1), 0 = none
+    pub fn floppy_types(&mut self) -> (u8, u8) {
+        let types = self.read_index(NVRAM_FLOPPY_TYPES);
+        (types >> 4, types & 0x0F)
+    }
 
-        self.write_register(CMOSRTCRegister::Seconds.as_u8()); // seconds
-        time.second = self.read_cmos();
+    /// The BIOS hard disk types, (disk 0, disk 1), 0 = none
+    pub fn disk_types(&mut self) -> (u8, u8) {
+        let types = self.read_index(NVRAM_DISK_TYPES);
+        (types >> 4, types & 0x0F)
+    }
 
-        self.write_register(CMOSRTCRegister::Minutes.as_u8());
-        time.minute = self.read_cmos();
+    /// Check the StatusB register
+    /// Check bit 2, value 4, 1 = binary mode set
+    pub fn is_bcd_mode(&mut self) -> bool {
+        self.read(CMOSRTCRegister::StatusB) & 0x04 == 0
+    }
 
-        self.write_register(CMOSRTCRegister::Hours.as_u8());
-        time.hour = self.read_cmos();
+    /// Check the status b register
+    /// Check bit 1, value = 2, 1 = hours are in 24 format
+    pub fn is_24_hour_format(&mut self) -> bool {
+        self.read(CMOSRTCRegister::StatusB) & 0x02 != 0
+    }
 
-        self.write_register(CMOSRTCRegister::DayOfMonth.as_u8());
-        time.day = self.read_cmos();
+    /// Check the status a register
+    /// Check bit 7, value = 128, 1 = the clock is being updated
+    pub fn is_update_in_progress(&mut self) -> bool {
+        self.read(CMOSRTCRegister::StatusA) & 0x80 != 0
+    }
 
-        self.write_register(CMOSRTCRegister::Month.as_u8());
-        time.month = self.read_cmos();
+    /// Read the clock registers once the current update has finished
+    ///
+    /// `century` is the register index of the century, if known.
+    fn read_time(&mut self, century: Option<u8>) -> RawTime {
+        while self.is_update_in_progress() {}
+
+        RawTime {
+            second: self.read(CMOSRTCRegister::Seconds),
+            minute: self.read(CMOSRTCRegister::Minutes),
+            hour: self.read(CMOSRTCRegister::Hours),
+            day: self.read(CMOSRTCRegister::DayOfMonth),
+            month: self.read(CMOSRTCRegister::Month),
+            year: self.read(CMOSRTCRegister::Year),
+            century: century.map(|index| self.read_index(index)),
+        }
+    }
 
-        self.write_register(CMOSRTCRegister::Year.as_u8());
-        time.year = self.read_cmos();
+    /// Write the clock registers
+    ///
+    /// The SET bit in StatusB stops the clock from updating while
+    /// the registers are written, the new time starts counting once
+    /// it is cleared. Values must already be in the RTC's format.
+    pub fn set_time(&mut self, time: RawTime, century: Option<u8>) {
+        const SET: u8 = 0x80;
+
+        let status_b = self.read(CMOSRTCRegister::StatusB);
+        self.write(CMOSRTCRegister::StatusB, status_b | SET);
+
+        self.write(CMOSRTCRegister::Seconds, time.second);
+        self.write(CMOSRTCRegister::Minutes, time.minute);
+        self.write(CMOSRTCRegister::Hours, time.hour);
+        self.write(CMOSRTCRegister::DayOfMonth, time.day);
+        self.write(CMOSRTCRegister::Month, time.month);
+        self.write(CMOSRTCRegister::Year, time.year);
+        if let (Some(index), Some(value)) = (century, time.century) {
+            self.write_index(index, value);
+        }
+
+        self.write(CMOSRTCRegister::StatusB, status_b & !SET);
+    }
 
-        time
+    /// Read the clock until two reads in a row agree
+    ///
+    /// An update can still start between the UIP check and the last
+    /// register read, the second read catches the torn value.
+    pub fn time_now(&mut self, century: Option<u8>) -> RawTime {
+        let mut last = self.read_time(century);
+        loop {
+            let time = self.read_time(century);
+            if time == last {
+                return time;
+            }
+            last = time;
+        }
     }
 }
@@ -0,0 +1,77 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+const EXT_LEAF_BASE: u32 = 0x8000_0000;
+const EXT_LEAF_FEATURES: u32 = 0x8000_0001;
+const EXT_LEAF_POWER: u32 = 0x8000_0007;
+
+/// Execute cpuid for the given leaf and subleaf
+///
+/// Returns `None` when the leaf is above the maximum
+/// leaf reported by the processor for its range.
+pub fn cpuid(leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    let base = leaf & EXT_LEAF_BASE;
+    let max = __cpuid(base).eax;
+    if leaf > max {
+        return None;
+    }
+
+    Some(__cpuid_count(leaf, subleaf))
+}
+
+/// Check if the CPU supports the no-execute page bit
+/// cpuid 0x80000001, edx bit 20
+pub fn has_nx() -> bool {
+    cpuid(EXT_LEAF_FEATURES, 0).is_some_and(|r| r.edx & (1 << 20) != 0)
+}
+
+/// Check if the CPU supports the machine check exception
+/// cpuid 0x1, edx bit 7
+pub fn has_mce() -> bool {
+    cpuid(0x1, 0).is_some_and(|r| r.edx & (1 << 7) != 0)
+}
+
+/// Check if the CPU supports the machine check architecture MSRs
+/// cpuid 0x1, edx bit 14
+pub fn has_mca() -> bool {
+    cpuid(0x1, 0).is_some_and(|r| r.edx & (1 << 14) != 0)
+}
+
+/// Check if the CPU has an on-chip local APIC
+/// cpuid 0x1, edx bit 9
+pub fn has_apic() -> bool {
+    cpuid(0x1, 0).is_some_and(|r| r.edx & (1 << 9) != 0)
+}
+
+/// Check if the local APIC timer supports TSC-deadline mode
+/// cpuid 0x1, ecx bit 24
+pub fn has_tsc_deadline() -> bool {
+    cpuid(0x1, 0).is_some_and(|r| r.ecx & (1 << 24) != 0)
+}
+
+/// Check if the TSC runs at a constant rate in all power states
+/// cpuid 0x80000007, edx bit 8
+pub fn has_invariant_tsc() -> bool {
+    cpuid(EXT_LEAF_POWER, 0).is_some_and(|r| r.edx & (1 << 8) != 0)
+}
+
+/// Check if the CPU supports supervisor mode execution prevention
+/// cpuid 0x7, ebx bit 7
+pub fn has_smep() -> bool {
+    cpuid(0x7, 0).is_some_and(|r| r.ebx & (1 << 7) != 0)
+}
+
+/// Check if the CPU supports supervisor mode access prevention
+/// cpuid 0x7, ebx bit 20
+pub fn has_smap() -> bool {
+    cpuid(0x7, 0).is_some_and(|r| r.ebx & (1 << 20) != 0)
+}
+
+/// Read the time stamp counter
+pub fn rdtsc() -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
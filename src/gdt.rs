@@ -1,27 +1,34 @@
-use core::arch::{asm, global_asm};
-
+use core::arch::global_asm;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::syscall;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 pub struct Selectors {
     pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
     pub tss_selector: SegmentSelector,
     pub user_code: SegmentSelector,
     pub user_data: SegmentSelector,
 }
 
+// syscall_entry and int80_entry call Rust code on the kernel stack,
+// which expects it 16 byte aligned
+#[repr(align(16))]
+struct Stack<const SIZE: usize>([u8; SIZE]);
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
 
         // Kernel stack to transition from ring 3 to ring 0
         tss.privilege_stack_table[0] = {
-            static mut KERNEL_STACK: [u8; 4096 * 5] = [0; 4096 * 5];
+            static mut KERNEL_STACK: Stack<{ 4096 * 5 }> = Stack([0; 4096 * 5]);
             let start = VirtAddr::from_ptr(&raw const KERNEL_STACK);
             let end = start + (4096 * 5) as u64;
             end
@@ -30,7 +37,7 @@ lazy_static! {
         // Double fault handler stack
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: Stack<STACK_SIZE> = Stack([0; STACK_SIZE]);
 
             let start = VirtAddr::from_ptr(&raw const STACK);
             let end = start + STACK_SIZE as u64;
@@ -44,15 +51,19 @@ lazy_static! {
 lazy_static! {
     pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
+        // syscall and sysret need kernel code, kernel data, user data
+        // and user code in this order, see syscall::init
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        let user_code = gdt.append(Descriptor::user_code_segment());
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
         let user_data = gdt.append(Descriptor::user_data_segment());
+        let user_code = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
 
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
                 tss_selector,
                 user_code,
                 user_data,
@@ -62,53 +73,141 @@ lazy_static! {
 }
 
 pub fn init_gdt() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
+        CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
 
-use x86_64::registers::rflags::RFlags;
-
-use crate::mem::USER_ENTRY;
-use crate::{gdt, println};
-
-/// Switch to user mode using iretq
-pub fn enter_user_mode() -> ! {
-    use crate::mem::*;
-
-    let user_cs = GDT.1.user_code.0; // User mode CS
-    let user_ds = GDT.1.user_data.0; // User mode DS/SS
-
-    let user_main_addr = user_main as u64;
-
-    println!("user_main is at: {:#x}", user_main_addr);
+/// Top of the stack the CPU switches to when entering ring 0 from ring 3
+pub fn kernel_stack_top() -> VirtAddr {
+    TSS.privilege_stack_table[0]
+}
 
-    unsafe {
-        asm!(
-            "mov rsp, {0}",      // Set RSP to user stack top
-            "push {1}",          // Push User Data Segment
-            "push {0}",          // Push User Stack Pointer
-            "pushfq",            // Push RFLAGS
-            "push {2}",          // Push User Code Segment
-            "push {3}",          // Push User Entry (RIP)
-            "iretq",             // Interrupt return (switch to user mode)
-            in(reg) USER_STACK_TOP,
-            in(reg) user_ds,  // Load User Data Segment dynamically
-            in(reg) user_cs,  // Load User Code Segment dynamically
-            in(reg) user_main,
-            options(noreturn)
-        );
-    }
-    
+extern "C" {
+    /// Entry point of the user program below
+    pub fn user_main() -> !;
 }
 
-#[no_mangle]
-pub extern "C" fn user_main() -> ! {
-    // User mode code.
-    println!("hello from ring 3");
-    loop {}
-}
\ No newline at end of file
+// The user program
+//
+// Lives in .user_text, which `mem::user_init` copies to the
+// randomised user image, and runs from that copy. It is written in
+// assembly so that nothing, not even a compiler generated call, can
+// reach outside of its own section: it only uses rip relative
+// references to its own labels.
+global_asm!(
+    ".pushsection .user_text, \"ax\"",
+    ".global user_main",
+    "user_main:",
+    // write(1, greeting, len)
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    lea rsi, [rip + .Lgreeting]",
+    "    lea rdx, [rip + .Lgreeting_end]",
+    "    sub rdx, rsi",
+    "    syscall",
+    // give the kernel the CPU once
+    "    mov eax, {yield_}",
+    "    syscall",
+    // compare the cost of the two entry paths
+    "    xor r12d, r12d",
+    "    call .Lbench",
+    "    mov r15, rax",
+    "    mov r12d, 1",
+    "    call .Lbench",
+    "    mov rbx, rax",
+    "    lea rsi, [rip + .Lsyscall_label]",
+    "    lea rdx, [rip + .Lint80_label]",
+    "    call .Lwrite",
+    "    mov rax, r15",
+    "    call .Lwrite_number",
+    "    lea rsi, [rip + .Lint80_label]",
+    "    lea rdx, [rip + .Lcycles]",
+    "    call .Lwrite",
+    "    mov rax, rbx",
+    "    call .Lwrite_number",
+    "    lea rsi, [rip + .Lcycles]",
+    "    lea rdx, [rip + .Lcycles_end]",
+    "    call .Lwrite",
+    // exit(0)
+    "    mov eax, {exit}",
+    "    xor edi, edi",
+    "    syscall",
+    "    ud2",
+    // rax = average cycles of a getpid, through int 0x80 if r12 is
+    // set and syscall otherwise
+    ".Lbench:",
+    "    rdtsc",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    mov r13, rax",
+    "    mov r14d, {calls}",
+    "3:",
+    "    mov eax, {getpid}",
+    "    test r12, r12",
+    "    jnz 4f",
+    "    syscall",
+    "    jmp 5f",
+    "4:",
+    "    int {int80}",
+    "5:",
+    "    dec r14",
+    "    jnz 3b",
+    "    rdtsc",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    sub rax, r13",
+    "    xor edx, edx",
+    "    mov ecx, {calls}",
+    "    div rcx",
+    "    ret",
+    // write [rsi, rdx) to stdout
+    ".Lwrite:",
+    "    sub rdx, rsi",
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    syscall",
+    "    ret",
+    // write rax in decimal to stdout, digits are built backwards
+    // in a buffer on the stack
+    ".Lwrite_number:",
+    "    sub rsp, 24",
+    "    lea rsi, [rsp + 24]",
+    "    mov ecx, 10",
+    "6:",
+    "    xor edx, edx",
+    "    div rcx",
+    "    add dl, 0x30", // '0'
+    "    dec rsi",
+    "    mov [rsi], dl",
+    "    test rax, rax",
+    "    jnz 6b",
+    "    lea rdx, [rsp + 24]",
+    "    call .Lwrite",
+    "    add rsp, 24",
+    "    ret",
+    ".Lgreeting:",
+    "    .ascii \"hello from user mode\\n\"",
+    ".Lgreeting_end:",
+    // back to back, each string ends at the label of the next
+    ".Lsyscall_label:",
+    "    .ascii \"syscall: \"",
+    ".Lint80_label:",
+    "    .ascii \", int 0x80: \"",
+    ".Lcycles:",
+    "    .ascii \" cycles per call\\n\"",
+    ".Lcycles_end:",
+    ".popsection",
+    write = const syscall::WRITE,
+    exit = const syscall::EXIT,
+    yield_ = const syscall::YIELD,
+    getpid = const syscall::GETPID,
+    int80 = const syscall::INT80_VECTOR,
+    calls = const 1000,
+);
@@ -0,0 +1,396 @@
+use alloc::boxed::Box;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::irq::{self, IrqHandle, IrqReturn};
+use crate::{acpi, apic, mem, println};
+
+// general registers
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+// capability bits
+const CAP_COUNTER_64: u64 = 1 << 13;
+
+// configuration bits
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// timer n configuration bits
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+/// Vector shared by the comparators routed past the ISA inputs
+pub const VECTOR: u8 = 0xE0;
+
+struct Hpet {
+    base: VirtAddr,
+    period_fs: u64, // length of one counter tick
+    timers: u8,
+    min_tick: u64,
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { read_volatile((self.base + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { write_volatile((self.base + reg).as_mut_ptr::<u64>(), value) }
+    }
+
+    fn timer_config(n: u8) -> u64 {
+        0x100 + 0x20 * n as u64
+    }
+
+    fn timer_comparator(n: u8) -> u64 {
+        0x108 + 0x20 * n as u64
+    }
+
+    fn ticks_for(&self, duration: Duration) -> u64 {
+        let fs = duration.as_nanos().saturating_mul(FEMTOS_PER_NANO as u128);
+        (fs / self.period_fs as u128).clamp(self.min_tick as u128, u64::MAX as u128) as u64
+    }
+
+    /// The I/O APIC inputs timer `n` can be routed to, as a bitmask
+    fn route_capability(&self, n: u8) -> u32 {
+        (self.read(Self::timer_config(n)) >> 32) as u32
+    }
+
+    /// A free ISA line whose GSI timer `n` can interrupt on
+    fn free_line(&self, n: u8) -> Option<(u8, u32)> {
+        let capability = self.route_capability(n);
+        (3..irq::LINES)
+            .filter(|&line| line != 8 && irq::handler_names(line).is_empty())
+            .filter_map(|line| Some((line, apic::isa_gsi(line)?)))
+            .find(|&(_, gsi)| gsi < 32 && capability & (1 << gsi) != 0)
+    }
+
+    /// Claim an I/O APIC input past the ISA range that timer `n`
+    /// can be routed to and no ISA line is wired to
+    fn claim_gsi(&self, n: u8) -> Option<u32> {
+        let capability = self.route_capability(n);
+        let isa = (0..irq::LINES)
+            .filter_map(apic::isa_gsi)
+            .filter(|&gsi| gsi < 32)
+            .fold(0u32, |mask, gsi| mask | 1 << gsi);
+        (irq::LINES as u32..32)
+            .filter(|&gsi| capability & (1 << gsi) != 0 && isa & (1 << gsi) == 0)
+            .find(|&gsi| CLAIMED_GSIS.fetch_or(1 << gsi, Ordering::Relaxed) & (1 << gsi) == 0)
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+// comparators handed out by `Comparator::new`, timer 0 drives the tick
+static ALLOCATED: AtomicU32 = AtomicU32::new(1);
+
+// counter value each one-shot comparator fires at, 0 when not armed
+static DEADLINES: [AtomicU64; 32] = [const { AtomicU64::new(0) }; 32];
+
+type Callback = Box<dyn FnMut() + Send>;
+
+static CALLBACKS: [Mutex<Option<Callback>>; 32] = [const { Mutex::new(None) }; 32];
+
+// I/O APIC inputs past the ISA range taken by comparators, and the
+// comparators interrupting on one of them through `VECTOR`
+static CLAIMED_GSIS: AtomicU32 = AtomicU32::new(0);
+static ON_VECTOR: AtomicU32 = AtomicU32::new(0);
+
+/// Find the HPET through its ACPI table, map it and start the counter
+///
+/// Returns false when there is no usable HPET.
+pub fn init() -> bool {
+    let Some(table) = acpi::find_table(b"HPET") else {
+        return false;
+    };
+
+    // body: block id (u32), base address as a generic address
+    // structure (12 bytes, address at byte 4), number, min tick
+    let body = table.body();
+    if body.len() < 20 || body[4] != 0 {
+        return false; // registers must be in memory space
+    }
+    let address = u64::from_le_bytes(body[8..16].try_into().unwrap());
+    let min_tick = u16::from_le_bytes([body[17], body[18]]) as u64;
+
+    let Ok(base) = mem::map_mmio(PhysAddr::new(address), 0x400) else {
+        return false;
+    };
+
+    let capabilities = unsafe { read_volatile((base + CAPABILITIES).as_ptr::<u64>()) };
+    let period_fs = capabilities >> 32;
+    if capabilities & CAP_COUNTER_64 == 0 || period_fs == 0 || period_fs > 100_000_000 {
+        return false;
+    }
+
+    let hpet = HPET.call_once(|| Hpet {
+        base,
+        period_fs,
+        timers: ((capabilities >> 8) & 0x1F) as u8 + 1,
+        min_tick: min_tick.max(1),
+    });
+
+    // every comparator starts disabled
+    for n in 0..hpet.timers {
+        let config = hpet.read(Hpet::timer_config(n));
+        hpet.write(
+            Hpet::timer_config(n),
+            config & !(TIMER_INT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+
+    // legacy replacement routing would take IRQ8 from the RTC
+    let config = hpet.read(CONFIGURATION);
+    hpet.write(
+        CONFIGURATION,
+        (config & !CONFIG_LEGACY_ROUTE) | CONFIG_ENABLE,
+    );
+
+    println!(
+        "HPET: {} timers, {}.{:06} MHz",
+        hpet.timers,
+        1_000_000_000 / period_fs,
+        1_000_000_000_000_000 / period_fs % 1_000_000
+    );
+    true
+}
+
+pub fn is_present() -> bool {
+    HPET.r#try().is_some()
+}
+
+/// Whether comparators 0 and 1 have taken over IRQ0 and IRQ8
+pub fn legacy_routing() -> bool {
+    HPET.r#try()
+        .is_some_and(|hpet| hpet.read(CONFIGURATION) & CONFIG_LEGACY_ROUTE != 0)
+}
+
+/// Main counter frequency in Hz
+pub fn frequency() -> Option<u64> {
+    HPET.r#try()
+        .map(|hpet| 1_000_000_000_000_000 / hpet.period_fs)
+}
+
+/// The free running main counter
+pub fn counter() -> Option<u64> {
+    HPET.r#try().map(|hpet| hpet.read(MAIN_COUNTER))
+}
+
+/// Convert a number of counter ticks to nanoseconds
+pub fn ticks_to_ns(ticks: u64) -> Option<u64> {
+    let hpet = HPET.r#try()?;
+    Some((ticks as u128 * hpet.period_fs as u128 / FEMTOS_PER_NANO as u128) as u64)
+}
+
+/// Drive the timer tick (ISA IRQ0) from comparator 0 at `hz`
+///
+/// The comparator is routed to the timer's GSI on the I/O APIC.
+/// Legacy replacement routing is never used since it would take
+/// the RTC's IRQ8 as well. Returns the tick length in ns, or `None`
+/// when the HPET cannot interrupt on IRQ0.
+pub fn start_periodic(hz: u32) -> Option<u64> {
+    let hpet = HPET.r#try()?;
+    let config = hpet.read(Hpet::timer_config(0));
+    if config & TIMER_PERIODIC_CAP == 0 {
+        return None;
+    }
+
+    let gsi = apic::isa_gsi(irq::TIMER)
+        .filter(|&gsi| gsi < 32 && hpet.route_capability(0) & (1 << gsi) != 0)?;
+
+    let period = hpet.ticks_for(Duration::from_nanos(1_000_000_000 / hz.max(1) as u64));
+    without_interrupts(|| {
+        let general = hpet.read(CONFIGURATION);
+        hpet.write(CONFIGURATION, general & !CONFIG_ENABLE);
+
+        let config = (config & !TIMER_ROUTE_MASK)
+            | (gsi as u64) << TIMER_ROUTE_SHIFT
+            | TIMER_INT_ENABLE
+            | TIMER_PERIODIC
+            | TIMER_VALUE_SET;
+        hpet.write(Hpet::timer_config(0), config);
+
+        // with VALUE_SET the first write sets the comparator, the second the period
+        let now = hpet.read(MAIN_COUNTER);
+        hpet.write(Hpet::timer_comparator(0), now + period);
+        hpet.write(Hpet::timer_comparator(0), period);
+
+        hpet.write(CONFIGURATION, general | CONFIG_ENABLE);
+    });
+
+    ticks_to_ns(period)
+}
+
+/// A comparator that fires a callback once per `arm`
+///
+/// Only available with the APICs. The comparator interrupts on a
+/// free ISA line whose GSI it can be routed to, or else on its own
+/// I/O APIC input past the ISA range, delivered on `VECTOR`.
+pub struct Comparator {
+    index: u8,
+    route: Route,
+}
+
+enum Route {
+    Isa(IrqHandle),
+    Gsi(u32),
+}
+
+impl Comparator {
+    /// Claim a free comparator and run `callback` whenever it fires
+    pub fn new(callback: impl FnMut() + Send + 'static) -> Option<Comparator> {
+        let hpet = HPET.r#try()?;
+
+        let Some(index) = (1..hpet.timers)
+            .find(|&n| ALLOCATED.fetch_or(1 << n, Ordering::Relaxed) & (1 << n) == 0)
+        else {
+            println!("HPET: no free comparator");
+            return None;
+        };
+
+        // an ISA line is edge triggered, an input of our own level triggered
+        let (line, gsi) = match hpet.free_line(index) {
+            Some((line, gsi)) => (Some(line), gsi),
+            None => match hpet.claim_gsi(index) {
+                Some(gsi) if apic::route_gsi(gsi, VECTOR) => (None, gsi),
+                claimed => {
+                    if let Some(gsi) = claimed {
+                        println!("HPET: no I/O APIC has GSI {}", gsi);
+                        CLAIMED_GSIS.fetch_and(!(1 << gsi), Ordering::Relaxed);
+                    } else {
+                        println!(
+                            "HPET: no free interrupt for comparator {}, it can use GSIs {:#x}",
+                            index,
+                            hpet.route_capability(index)
+                        );
+                    }
+                    ALLOCATED.fetch_and(!(1 << index), Ordering::Relaxed);
+                    return None;
+                }
+            },
+        };
+        let level = if line.is_some() { 0 } else { TIMER_LEVEL };
+
+        let config = hpet.read(Hpet::timer_config(index));
+        hpet.write(
+            Hpet::timer_config(index),
+            (config & !(TIMER_ROUTE_MASK | TIMER_PERIODIC | TIMER_INT_ENABLE | TIMER_LEVEL))
+                | (gsi as u64) << TIMER_ROUTE_SHIFT
+                | level,
+        );
+        hpet.write(INTERRUPT_STATUS, 1 << index);
+        without_interrupts(|| *CALLBACKS[index as usize].lock() = Some(Box::new(callback)));
+
+        let route = match line {
+            Some(line) => Route::Isa(irq::register(line, "hpet", move || expire(hpet, index))),
+            None => {
+                ON_VECTOR.fetch_or(1 << index, Ordering::Relaxed);
+                Route::Gsi(gsi)
+            }
+        };
+
+        Some(Comparator { index, route })
+    }
+    /// Fire once `after` has passed, replacing any earlier deadline
+    pub fn arm(&self, after: Duration) {
+        let Some(hpet) = HPET.r#try() else {
+            return;
+        };
+
+        without_interrupts(|| {
+            let deadline = hpet
+                .read(MAIN_COUNTER)
+                .saturating_add(hpet.ticks_for(after));
+            DEADLINES[self.index as usize].store(deadline, Ordering::Relaxed);
+
+            let config = hpet.read(Hpet::timer_config(self.index));
+            hpet.write(Hpet::timer_comparator(self.index), deadline);
+            hpet.write(Hpet::timer_config(self.index), config | TIMER_INT_ENABLE);
+
+            // the comparator only matches on equality, if the counter went
+            // past it meanwhile move it a minimum tick ahead of the counter
+            let mut comparator = deadline;
+            loop {
+                let now = hpet.read(MAIN_COUNTER);
+                if now < comparator {
+                    break;
+                }
+                comparator = now.saturating_add(hpet.min_tick);
+                hpet.write(Hpet::timer_comparator(self.index), comparator);
+            }
+        });
+    }
+
+    pub fn cancel(&self) {
+        if let Some(hpet) = HPET.r#try() {
+            without_interrupts(|| disarm(hpet, self.index));
+        }
+    }
+}
+
+impl Drop for Comparator {
+    fn drop(&mut self) {
+        self.cancel();
+        match self.route {
+            Route::Isa(handle) => {
+                irq::unregister(handle);
+            }
+            Route::Gsi(gsi) => {
+                apic::mask_gsi(gsi);
+                ON_VECTOR.fetch_and(!(1 << self.index), Ordering::Relaxed);
+                CLAIMED_GSIS.fetch_and(!(1 << gsi), Ordering::Relaxed);
+            }
+        }
+        without_interrupts(|| *CALLBACKS[self.index as usize].lock() = None);
+        ALLOCATED.fetch_and(!(1 << self.index), Ordering::Relaxed);
+    }
+}
+
+fn disarm(hpet: &Hpet, index: u8) {
+    DEADLINES[index as usize].store(0, Ordering::Relaxed);
+    let config = hpet.read(Hpet::timer_config(index));
+    hpet.write(Hpet::timer_config(index), config & !TIMER_INT_ENABLE);
+}
+
+/// Run the callback of comparator `index` if its deadline has passed
+fn expire(hpet: &Hpet, index: u8) -> IrqReturn {
+    let deadline = DEADLINES[index as usize].load(Ordering::Relaxed);
+    if deadline == 0 || hpet.read(MAIN_COUNTER) < deadline {
+        return IrqReturn::NotMine;
+    }
+
+    disarm(hpet, index);
+    if let Some(callback) = CALLBACKS[index as usize].lock().as_mut() {
+        callback();
+    }
+    IrqReturn::Handled
+}
+
+/// Handle `VECTOR` for the comparators on inputs of their own
+///
+/// These are level triggered and hold the input until their
+/// status bit is cleared.
+pub fn handle_interrupt() {
+    if let Some(hpet) = HPET.r#try() {
+        let pending = hpet.read(INTERRUPT_STATUS) & ON_VECTOR.load(Ordering::Relaxed) as u64;
+        hpet.write(INTERRUPT_STATUS, pending);
+        for index in (0..hpet.timers).filter(|&n| pending & (1 << n) != 0) {
+            expire(hpet, index);
+        }
+    }
+    apic::end_of_interrupt();
+}
@@ -1,22 +1,25 @@
 use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::irq::{self, IrqReturn};
+use crate::task::{self, ExitReason};
+use crate::{apic, cpu, hpet, shell, syscall, usercopy, vga_buffer};
 use crate::{print, println};
+use core::fmt;
 use lazy_static::lazy_static;
 use pc_keyboard::DecodedKey;
 use pic8259::ChainedPics;
-use x86_64::instructions::hlt;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode,
+};
+use x86_64::{PrivilegeLevel, VirtAddr};
 
-pub const PIC_1_OFFSET: u8 = 32;
+// The I/O APIC delivers the ISA lines on 32-47 (see irq::vector), the
+// PICs get the next 16 vectors so a stray PIC interrupt can always be
+// told apart from an I/O APIC one
+pub const PIC_1_OFFSET: u8 = 48;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum IntIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
@@ -24,8 +27,37 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available
+            .set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point
+            .set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point
+            .set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception
+            .set_handler_fn(control_protection_handler);
+        idt.hv_injection_exception
+            .set_handler_fn(hv_injection_handler);
+        idt.vmm_communication_exception
+            .set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_handler);
 
         unsafe {
             idt.double_fault
@@ -33,33 +65,380 @@ lazy_static! {
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX);
         }
 
-        idt[IntIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
-        idt[IntIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+        for line in 0..irq::LINES {
+            idt[irq::vector(line)].set_handler_fn(irq::STUBS[line as usize]);
+            idt[irq::pic_vector(line)].set_handler_fn(irq::PIC_STUBS[line as usize]);
+        }
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(apic_spurious_handler);
+        idt[apic::TIMER_VECTOR].set_handler_fn(apic_timer_handler);
+        idt[hpet::VECTOR].set_handler_fn(hpet_handler);
+
+        // reachable from ring 3 with int 0x80
+        unsafe {
+            idt[syscall::INT80_VECTOR]
+                .set_handler_addr(syscall::int80_entry_addr())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
 
         idt
     };
 }
 
-/// Interrupt handlers
+/// Exception diagnostics
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    println!("exception: page fault");
+/// Whether an exception was raised by ring 3 code,
+/// decided by the RPL of the saved CS
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
+/// Print the exception, where it came from, the saved
+/// registers and the bytes at the faulting instruction
+fn report_exception(name: &str, stack_frame: &InterruptStackFrame) {
+    let origin = if from_user(stack_frame) {
+        "user"
+    } else {
+        "kernel"
+    };
+
+    println!("exception: {} in {} mode", name, origin);
+    println!(
+        "rip {:#x} cs {:#x} rflags {:#x} rsp {:#x} ss {:#x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment.0,
+        stack_frame.cpu_flags.bits(),
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment.0
+    );
     println!(
-        "accessed address: {:?}",
-        x86_64::registers::control::Cr2::read().unwrap_or(VirtAddr::zero())
+        "cr0 {:#x} cr3 {:#x} cr4 {:#x}",
+        Cr0::read_raw(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+
+    // user code is read with the user copy rules, kernel code
+    // may be garbage after a bad jump so read it without faulting
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let mut code = [0u8; 16];
+    let read = if from_user(stack_frame) {
+        usercopy::copy_from_user(&mut code, rip)
+    } else {
+        usercopy::read_nofault(&mut code, rip)
+    };
+
+    match read {
+        Ok(()) => {
+            print!("code:");
+            for byte in code {
+                print!(" {:02x}", byte);
+            }
+            println!();
+        }
+        Err(_) => println!("code: <unreadable>"),
+    }
+}
+
+/// Report an exception that can arrive while the screen lock is
+/// held, e.g. an NMI, with only its name and rip
+///
+/// The report is dropped rather than waiting for the lock.
+fn report_exception_nolock(name: &str, stack_frame: &InterruptStackFrame) {
+    vga_buffer::try_print(format_args!(
+        "exception: {} at rip {:#x}\n",
+        name,
+        stack_frame.instruction_pointer.as_u64()
+    ));
+}
+
+/// Handle an exception the faulting code cannot continue from
+///
+/// A user task raising it is terminated and the kernel resumes,
+/// in the kernel itself it is fatal.
+fn fault(
+    name: &'static str,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: Option<u64>,
+    detail: Option<fmt::Arguments>,
+) {
+    fault_at(name, stack_frame, error_code, None, detail);
+}
+
+/// `fault` for exceptions that come with a faulting address
+fn fault_at(
+    name: &'static str,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: Option<u64>,
+    address: Option<u64>,
+    detail: Option<fmt::Arguments>,
+) {
+    report_exception(name, stack_frame);
+    if let Some(detail) = detail {
+        println!("{}", detail);
+    }
+
+    if from_user(stack_frame) {
+        let reason = ExitReason::Fault {
+            exception: name,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            error_code,
+            address,
+        };
+        task::exit_current(stack_frame, reason);
+        return;
+    }
+
+    panic!("exception: {}", name);
+}
+
+/// Decode the selector error code pushed by #TS, #NP, #SS and #GP
+fn selector_error(error_code: u64) -> SelectorErrorCode {
+    SelectorErrorCode::new_truncate(error_code)
+}
+
+/// Resume at the exception table fixup if the faulting
+/// instruction is one of the fault-tolerant copy routines
+fn try_fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let Some(fixup) = usercopy::fixup(stack_frame.instruction_pointer) else {
+        return false;
+    };
+
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = fixup);
+    }
+    true
+}
+
+/// Exception handlers
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    fault("divide error (#DE)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    report_exception_nolock("debug (#DB)", &stack_frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    report_exception_nolock("non-maskable interrupt", &stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(mut stack_frame: InterruptStackFrame) {
+    fault("overflow (#OF)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn bound_range_handler(mut stack_frame: InterruptStackFrame) {
+    fault("bound range exceeded (#BR)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    fault("invalid opcode (#UD)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(mut stack_frame: InterruptStackFrame) {
+    fault("device not available (#NM)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let selector = selector_error(error_code);
+    fault(
+        "invalid TSS (#TS)",
+        &mut stack_frame,
+        Some(error_code),
+        Some(format_args!("{:?}", selector)),
     );
-    println!("error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let selector = selector_error(error_code);
+    fault(
+        "segment not present (#NP)",
+        &mut stack_frame,
+        Some(error_code),
+        Some(format_args!("{:?}", selector)),
+    );
+}
 
-    loop {
-        hlt();
+extern "x86-interrupt" fn stack_segment_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let selector = selector_error(error_code);
+    if selector.is_null() {
+        fault(
+            "stack segment fault (#SS)",
+            &mut stack_frame,
+            Some(error_code),
+            Some(format_args!("non-canonical or limit violation")),
+        );
+    } else {
+        fault(
+            "stack segment fault (#SS)",
+            &mut stack_frame,
+            Some(error_code),
+            Some(format_args!("{:?}", selector)),
+        );
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn general_protection_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    // a nofault read of a non-canonical address lands here
+    if !from_user(&stack_frame) && try_fixup(&mut stack_frame) {
+        return;
+    }
+
+    let selector = selector_error(error_code);
+    if selector.is_null() {
+        fault(
+            "general protection fault (#GP)",
+            &mut stack_frame,
+            Some(error_code),
+            Some(format_args!(
+                "no selector (privileged instruction or non-canonical address)"
+            )),
+        );
+    } else {
+        fault(
+            "general protection fault (#GP)",
+            &mut stack_frame,
+            Some(error_code),
+            Some(format_args!("{:?}", selector)),
+        );
+    }
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(mut stack_frame: InterruptStackFrame) {
+    fault("x87 floating point (#MF)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fault(
+        "alignment check (#AC)",
+        &mut stack_frame,
+        Some(error_code),
+        None,
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    const IA32_MCG_CAP: u32 = 0x179;
+    const IA32_MCG_STATUS: u32 = 0x17A;
+    const IA32_MC0_STATUS: u32 = 0x401;
+
+    report_exception("machine check (#MC)", &stack_frame);
+
+    if cpu::has_mca() {
+        unsafe {
+            let banks = Msr::new(IA32_MCG_CAP).read() & 0xff;
+            println!("mcg status: {:#x}", Msr::new(IA32_MCG_STATUS).read());
+
+            // only print banks holding a valid error (bit 63)
+            for bank in 0..banks as u32 {
+                let status = Msr::new(IA32_MC0_STATUS + bank * 4).read();
+                if status & (1 << 63) != 0 {
+                    println!("bank {}: status {:#x}", bank, status);
+                }
+            }
+        }
+    }
+
+    panic!("exception: machine check");
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(mut stack_frame: InterruptStackFrame) {
+    fault("SIMD floating point (#XM)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn virtualization_handler(mut stack_frame: InterruptStackFrame) {
+    fault("virtualization (#VE)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn control_protection_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let cause = match error_code & 0x7fff {
+        1 => "near ret",
+        2 => "far ret/iret",
+        3 => "missing endbranch",
+        4 => "rstorssp",
+        5 => "setssbsy",
+        _ => "unknown",
+    };
+
+    fault(
+        "control protection (#CP)",
+        &mut stack_frame,
+        Some(error_code),
+        Some(format_args!("cause: {}", cause)),
+    );
+}
+
+extern "x86-interrupt" fn hv_injection_handler(mut stack_frame: InterruptStackFrame) {
+    fault("hypervisor injection (#HV)", &mut stack_frame, None, None);
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fault(
+        "VMM communication (#VC)",
+        &mut stack_frame,
+        Some(error_code),
+        Some(format_args!("error code: {:#x}", error_code)),
+    );
+}
+
+extern "x86-interrupt" fn security_handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    fault(
+        "security exception (#SX)",
+        &mut stack_frame,
+        Some(error_code),
+        Some(format_args!("error code: {:#x}", error_code)),
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    // a user copy hit a bad address, resume at its fixup
+    if !from_user(&stack_frame) && try_fixup(&mut stack_frame) {
+        return;
+    }
+
+    let accessed = x86_64::registers::control::Cr2::read().unwrap_or(VirtAddr::zero());
+
+    fault_at(
+        "page fault (#PF)",
+        &mut stack_frame,
+        Some(error_code.bits()),
+        Some(accessed.as_u64()),
+        Some(format_args!(
+            "accessed address: {:?}\nerror code: {:?}",
+            accessed, error_code
+        )),
+    );
+}
+
+/// Interrupt handlers
+
+fn keyboard_handler() -> IrqReturn {
     use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -78,24 +457,43 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
 
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{character}"),
-                DecodedKey::RawKey(raw) => print!("{raw:?}"),
-            }
+        if let Some(DecodedKey::Unicode(character)) = keyboard.process_keyevent(key_event) {
+            shell::push_key(character);
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(IntIndex::Keyboard as u8);
-    };
+    IrqReturn::Handled
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// The local APIC raises this when an interrupt goes away before
+/// it is delivered, it must not be acknowledged
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
+/// The APIC timer only wakes the CPU from `timer::idle`,
+/// which then runs the timers that are due
+extern "x86-interrupt" fn apic_timer_handler(_stack_frame: InterruptStackFrame) {
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn hpet_handler(_stack_frame: InterruptStackFrame) {
+    hpet::handle_interrupt();
+}
+
+/// Read the in-service registers of the primary and secondary PIC
+pub fn pic_in_service() -> [u8; 2] {
+    use x86_64::instructions::port::Port;
+
+    // OCW3: the next read of the command port returns the ISR
+    const READ_ISR: u8 = 0x0B;
+
+    let _pics = PICS.lock();
+    let mut primary: Port<u8> = Port::new(0x20);
+    let mut secondary: Port<u8> = Port::new(0xA0);
     unsafe {
-        PICS.lock().notify_end_of_interrupt(IntIndex::Timer as u8);
-    };
+        primary.write(READ_ISR);
+        secondary.write(READ_ISR);
+        [primary.read(), secondary.read()]
+    }
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -115,9 +513,22 @@ pub fn init_pic() {
     unsafe {
         PICS.lock().initialize();
     }
+    irq::apply_pic_masks();
+}
+
+/// Register the handlers of the built-in devices, needs the heap
+pub fn init_irqs() {
+    irq::register(irq::KEYBOARD, "keyboard", keyboard_handler);
 }
 
 pub fn init_idt() {
+    // machine checks shut the CPU down unless CR4.MCE is set
+    if cpu::has_mce() {
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+        }
+    }
+
     IDT.load();
     x86_64::instructions::interrupts::enable();
 }
@@ -0,0 +1,300 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+use crate::interrupts::{pic_in_service, PICS, PIC_1_OFFSET};
+use crate::{apic, println};
+
+/// Number of ISA interrupt lines
+pub const LINES: u8 = 16;
+
+// well known ISA lines
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const CASCADE: u8 = 2; // the secondary PIC, never raised itself
+
+/// What a handler did with an interrupt on a shared line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The interrupt came from this handler's device
+    Handled,
+    /// The interrupt was for another device on the line
+    NotMine,
+}
+
+/// Identifies a registered handler so it can be removed again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    line: u8,
+    id: u64,
+}
+
+struct Action {
+    id: u64,
+    name: &'static str,
+    handler: Box<dyn FnMut() -> IrqReturn + Send>,
+}
+
+static ACTIONS: [Mutex<Vec<Action>>; LINES as usize] =
+    [const { Mutex::new(Vec::new()) }; LINES as usize];
+
+static COUNTS: [AtomicU64; LINES as usize] = [const { AtomicU64::new(0) }; LINES as usize];
+static UNHANDLED: [AtomicU64; LINES as usize] = [const { AtomicU64::new(0) }; LINES as usize];
+
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// bit n set = line n masked, everything but the cascade starts masked
+static MASKS: AtomicU16 = AtomicU16::new(!(1 << CASCADE));
+
+// first vector the I/O APIC delivers ISA lines on
+const IO_APIC_OFFSET: u8 = 32;
+
+/// The IDT vector the I/O APIC delivers ISA `line` on
+pub const fn vector(line: u8) -> u8 {
+    IO_APIC_OFFSET + line
+}
+
+/// The IDT vector the 8259 PICs raise ISA `line` on
+pub const fn pic_vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+/// IDT entry points for the I/O APIC vectors, all feeding `dispatch`
+pub const STUBS: [HandlerFunc; LINES as usize] = [
+    irq_stub::<0>,
+    irq_stub::<1>,
+    irq_stub::<2>,
+    irq_stub::<3>,
+    irq_stub::<4>,
+    irq_stub::<5>,
+    irq_stub::<6>,
+    irq_stub::<7>,
+    irq_stub::<8>,
+    irq_stub::<9>,
+    irq_stub::<10>,
+    irq_stub::<11>,
+    irq_stub::<12>,
+    irq_stub::<13>,
+    irq_stub::<14>,
+    irq_stub::<15>,
+];
+
+/// IDT entry points for the PIC vectors
+pub const PIC_STUBS: [HandlerFunc; LINES as usize] = [
+    pic_stub::<0>,
+    pic_stub::<1>,
+    pic_stub::<2>,
+    pic_stub::<3>,
+    pic_stub::<4>,
+    pic_stub::<5>,
+    pic_stub::<6>,
+    pic_stub::<7>,
+    pic_stub::<8>,
+    pic_stub::<9>,
+    pic_stub::<10>,
+    pic_stub::<11>,
+    pic_stub::<12>,
+    pic_stub::<13>,
+    pic_stub::<14>,
+    pic_stub::<15>,
+];
+
+extern "x86-interrupt" fn irq_stub<const LINE: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(LINE);
+}
+
+extern "x86-interrupt" fn pic_stub<const LINE: u8>(_stack_frame: InterruptStackFrame) {
+    if apic::is_active() {
+        stray_pic_interrupt(LINE);
+    } else {
+        dispatch(LINE);
+    }
+}
+
+/// An interrupt from the PICs after they were masked for the APICs
+///
+/// Only their spurious IRQ7/IRQ15 get through. These come as ExtINT,
+/// which the local APIC does not track, so it gets no EOI.
+fn stray_pic_interrupt(line: u8) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    if line == 15 {
+        end_of_pic_interrupt(CASCADE);
+    }
+}
+
+/// Run every handler chained on `line`, then acknowledge it
+///
+/// All handlers run since an edge triggered line may carry
+/// interrupts from several devices at once.
+fn dispatch(line: u8) {
+    if is_spurious(line) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+
+        // a line masked while its interrupt was already on the way
+        // still occupies the local APIC, the primary PIC did raise
+        // the cascade for a spurious IRQ15
+        if apic::is_active() {
+            apic::end_of_interrupt();
+        } else if line == 15 {
+            end_of_pic_interrupt(CASCADE);
+        }
+        return;
+    }
+
+    COUNTS[line as usize].fetch_add(1, Ordering::Relaxed);
+
+    let mut actions = ACTIONS[line as usize].lock();
+    let mut handled = false;
+    for action in actions.iter_mut() {
+        handled |= (action.handler)() == IrqReturn::Handled;
+    }
+    if !handled {
+        let previous = UNHANDLED[line as usize].fetch_add(1, Ordering::Relaxed);
+        if actions.is_empty() && previous == 0 {
+            let vector = if apic::is_active() {
+                vector(line)
+            } else {
+                pic_vector(line)
+            };
+            println!("unexpected IRQ {} (vector {})", line, vector);
+        }
+    }
+    drop(actions);
+
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        end_of_pic_interrupt(line);
+    }
+}
+
+fn end_of_pic_interrupt(line: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(pic_vector(line)) };
+}
+
+/// Whether an interrupt on `line` was raised without a device asking
+///
+/// A PIC raises IRQ7 (IRQ15 on the secondary) when a request goes
+/// away before it is acknowledged, the ISR bit is then clear and
+/// the interrupt must not get an EOI. With the APICs in charge an
+/// interrupt on a masked line was already on its way when the line
+/// was masked.
+fn is_spurious(line: u8) -> bool {
+    if apic::is_active() {
+        return is_masked(line);
+    }
+
+    match line {
+        7 => pic_in_service()[0] & (1 << 7) == 0,
+        15 => pic_in_service()[1] & (1 << 7) == 0,
+        _ => false,
+    }
+}
+
+/// Add `handler` to the chain of `line` and unmask the line
+///
+/// Handlers run with interrupts disabled and must not register
+/// or unregister handlers themselves.
+pub fn register<F>(line: u8, name: &'static str, handler: F) -> IrqHandle
+where
+    F: FnMut() -> IrqReturn + Send + 'static,
+{
+    assert!(line < LINES && line != CASCADE, "invalid IRQ line {line}");
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    without_interrupts(|| {
+        ACTIONS[line as usize].lock().push(Action {
+            id,
+            name,
+            handler: Box::new(handler),
+        });
+    });
+    unmask(line);
+
+    IrqHandle { line, id }
+}
+
+/// Remove a handler, the line is masked again once its chain is empty
+///
+/// Returns false if the handler was already removed.
+pub fn unregister(handle: IrqHandle) -> bool {
+    let (removed, empty) = without_interrupts(|| {
+        let mut actions = ACTIONS[handle.line as usize].lock();
+        let before = actions.len();
+        actions.retain(|action| action.id != handle.id);
+        (actions.len() != before, actions.is_empty())
+    });
+
+    if empty {
+        mask(handle.line);
+    }
+    removed
+}
+
+/// Names of the handlers chained on `line`, in call order
+pub fn handler_names(line: u8) -> Vec<&'static str> {
+    without_interrupts(|| {
+        ACTIONS[line as usize]
+            .lock()
+            .iter()
+            .map(|action| action.name)
+            .collect()
+    })
+}
+
+/// Interrupts received on `line`
+pub fn count(line: u8) -> u64 {
+    COUNTS[line as usize].load(Ordering::Relaxed)
+}
+
+/// Spurious interrupts seen on IRQ7/IRQ15 or masked lines
+pub fn spurious() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Interrupts on `line` that no handler claimed
+pub fn unhandled(line: u8) -> u64 {
+    UNHANDLED[line as usize].load(Ordering::Relaxed)
+}
+
+pub fn is_masked(line: u8) -> bool {
+    MASKS.load(Ordering::Relaxed) & (1 << line) != 0
+}
+
+pub fn mask(line: u8) {
+    set_masked(line, true);
+}
+
+pub fn unmask(line: u8) {
+    set_masked(line, false);
+}
+
+fn set_masked(line: u8, masked: bool) {
+    let bit = 1 << line;
+    without_interrupts(|| {
+        if masked {
+            MASKS.fetch_or(bit, Ordering::Relaxed);
+        } else {
+            MASKS.fetch_and(!bit, Ordering::Relaxed);
+        }
+
+        if apic::is_active() {
+            apic::set_isa_irq_masked(line, masked);
+        } else {
+            apply_pic_masks();
+        }
+    });
+}
+
+/// Load the line masks into the 8259 PICs
+pub fn apply_pic_masks() {
+    let masks = MASKS.load(Ordering::Relaxed);
+    unsafe {
+        PICS.lock().write_masks(masks as u8, (masks >> 8) as u8);
+    }
+}
@@ -4,69 +4,101 @@
 
 extern crate alloc;
 
-mod cmos;
+pub mod acpi;
+pub mod apic;
+pub mod cmdline;
+pub mod clock;
+pub mod cmos;
+pub mod cpu;
 pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod irq;
 pub mod mem;
+pub mod pit;
+pub mod random;
+pub mod rtc;
+pub mod shell;
+pub mod statusbar;
+pub mod syscall;
+pub mod task;
 pub mod time;
+pub mod timer;
+pub mod timezone;
+pub mod usercopy;
 pub mod vga_buffer;
+pub mod wallclock;
 
 use crate::gdt::init_gdt;
-use crate::interrupts::{init_idt, init_pic};
+use crate::interrupts::{init_idt, init_irqs, init_pic};
 use bootloader::{entry_point, BootInfo};
-use gdt::user_main;
 use x86_64::structures::paging::mapper::MapToError;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, Translate};
 use core::panic::PanicInfo;
-use mem::BootInfoFrameAllocator;
+use mem::{BootInfoFrameAllocator, UserLayout};
 use time::DateTime;
 use vga_buffer::{set_print_color, ColorDesc};
 use x86_64::{PrivilegeLevel, VirtAddr};
 
-pub fn init(boot_info: &'static BootInfo) {
+pub fn init(boot_info: &'static BootInfo) -> UserLayout {
     init_pic();
     init_gdt();
     init_idt();
+    mem::enable_nx_and_wp();
 
     // init heap with boot info
     let phy_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
     let mut mapper = unsafe { mem::new_offset_page_table(phy_mem_offset) };
-    let virt_addr: VirtAddr = VirtAddr::new(user_main as *const () as u64);
+    mem::protect_kernel(&mut mapper).expect("failed to protect kernel sections");
+    mem::randomize_layout(&mapper);
+    mem::heap_init(&mut mapper, &mut frame_allocator).expect("failed to map the kernel heap");
 
-    
-    let frame = frame_allocator
-        .allocate_frame()
-        .unwrap();
-    let flags =
-        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-    let page = Page::containing_address(virt_addr);
+    let user_layout =
+        mem::user_init(&mut mapper, &mut frame_allocator).expect("failed to set up user memory");
+    user_layout
+        .validate(&mapper)
+        .expect("invalid user memory layout");
 
     unsafe {
-        mapper.map_to(page, frame, flags, &mut frame_allocator).unwrap().flush();
+        mem::fix_bootloader_wx(phy_mem_offset, &boot_info.memory_map);
+        mem::check_wx(phy_mem_offset);
     }
 
-    println!("mapped");
+    usercopy::init();
+    syscall::init();
+    mem::init_kernel_memory(mapper, frame_allocator);
+    cmdline::init();
+    timezone::init();
 
-    mem::heap_init(&mut mapper, &mut frame_allocator).expect("hello");
-
-
-
-
-    if mapper.translate_addr(virt_addr).is_some() {
-        panic!("user_main is not mapped! {:?}", virt_addr);
+    acpi::init();
+    if !apic::init() {
+        println!("APIC unavailable, using the 8259 PICs");
     }
+    hpet::init();
+    init_irqs();
+    timer::init(timer::DEFAULT_FREQUENCY);
+    clock::init();
+    rtc::init();
+    wallclock::init();
+    statusbar::init();
+    apic::init_timer();
+
+    user_layout
 }
 
 entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    init(boot_info);
+    let user_layout = init(boot_info);
     println!("hello");
 
-    gdt::enter_user_mode();
+    let mut user_task = task::UserTask::new(user_layout);
+    let reason = user_task.run();
+    println!("task {} ended: {:?}", user_task.pid, reason);
 
+    let mut shell = shell::Shell::new();
     loop {
-        x86_64::instructions::hlt();
+        shell.poll();
+        timer::idle(shell::has_input);
     }
 }
 
@@ -74,6 +106,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 fn panic(info: &PanicInfo) -> ! {
     println!("panic! {info}");
 
+    // nothing is left to wake up for
+    x86_64::instructions::interrupts::disable();
     loop {
         x86_64::instructions::hlt();
     }
@@ -1,48 +1,606 @@
-use bootloader::bootinfo::MemoryMap;
+use crate::gdt::user_main;
+use crate::println;
+use crate::random::Rng;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
-        mapper::{MapToError, MapperFlush},
+        mapper::{FlagUpdateError, MapToError, MapperFlush, TranslateResult},
+        page::PageRangeInclusive,
+        page_table::PageTableEntry,
         FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
-        Size4KiB,
+        Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
-const HEAP_START: u64 = 0x4444_4444_0000;
+const PAGE_SIZE: u64 = 4096;
 const HEAP_SIZE: u64 = 100 * 1024;
-pub const USER_ENTRY: u64 = 0x111_1111_0000;
 pub const USER_SIZE: u64 = 100 * 1024;
-pub const USER_STACK_TOP: u64 = 0x111_1112_0000; // Stack grows downward
 pub const USER_STACK_SIZE: u64 = 100 * 1024;
 
+// Windows the heap, user image and user stack are randomly placed
+// in at boot, see randomize_layout. The user windows are disjoint
+// so the image and stack can never overlap. Only the parts whose
+// level 4 entry the bootloader left unused are taken.
+const HEAP_WINDOW: Range<u64> = 0x4444_0000_0000..0x4448_0000_0000;
+const USER_ENTRY_WINDOW: Range<u64> = 0x100_0000_0000..0x200_0000_0000;
+const USER_STACK_WINDOW: Range<u64> = 0x600_0000_0000..0x700_0000_0000; // Stack grows downward
+
+// BIOS data, option ROMs and the VGA buffer, identity mapped by the bootloader
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+// Device registers (local APIC, I/O APIC, ...) are mapped here, see map_mmio
+const MMIO_WINDOW: Range<u64> = 0x5555_0000_0000..0x5556_0000_0000;
+
+// bytes mapped by one level 4 entry
+const L4_SPAN: u64 = 1 << 39;
+
+/// Base addresses chosen at boot
+#[derive(Debug)]
+pub struct AddressLayout {
+    pub heap_start: u64,
+    pub user_entry: u64,
+    pub user_stack_top: u64,
+}
+
+static ADDRESS_LAYOUT: Once<AddressLayout> = Once::new();
+
+/// The kernel page tables and frame allocator, kept for
+/// mappings made after boot such as device registers
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+    next_mmio: u64,
+    mmio_end: u64,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
 #[global_allocator]
 static ALLOCATOR: Lock<LinkedAllocator> = Lock::new(LinkedAllocator::new());
 
+// bytes handed out by the allocator, including its padding
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+// Section boundaries exported by linker.ld, all page aligned
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __user_text_start: u8;
+    static __user_text_end: u8;
+}
+
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
-pub fn stack_init(
-    mapper: &mut impl Mapper<Size4KiB>,
+/// Hand the boot page tables and frame allocator over to the kernel
+pub fn init_kernel_memory(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| mapper.phys_offset());
+    let mmio = unused_parts(mapper.level_4_table(), MMIO_WINDOW)
+        .next()
+        .expect("MMIO window already in use");
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+        next_mmio: mmio.start,
+        mmio_end: mmio.end,
+    });
+}
+
+/// Run `f` with exclusive access to the kernel page tables
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.lock();
+        f(memory.as_mut().expect("kernel memory used before init"))
+    })
+}
+
+/// Virtual address of `addr` in the bootloader's physical memory window
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET
+        .r#try()
+        .expect("physical memory offset used before init");
+    *offset + addr.as_u64()
+}
+
+/// Whether ring 3 may read `page`, or also write it with `write`
+///
+/// Checks every level of the active page tables, the CPU only
+/// allows user access when all of them do. False before
+/// `init_kernel_memory`.
+pub fn user_page_accessible(page: Page, write: bool) -> bool {
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.r#try() else {
+        return false;
+    };
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let addr = page.start_address();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table: &PageTable = unsafe { active_level_4_table(offset) };
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &table[index];
+        if !entry.flags().contains(required) {
+            return false;
+        }
+        if level == indices.len() - 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table = unsafe { &*(offset + entry.addr().as_u64()).as_ptr() };
+    }
+    true
+}
+
+/// Map `size` bytes of device registers at `phys` as uncached memory
+///
+/// Returns the virtual address `phys` is mapped at.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let frames = PhysFrame::range_inclusive(first, last);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | no_execute();
+
+    with_kernel_memory(|memory| {
+        let base = memory.next_mmio;
+        let len = (last.start_address() - first.start_address()) + PAGE_SIZE;
+        if base + len > memory.mmio_end {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        for (i, frame) in frames.enumerate() {
+            let page = Page::containing_address(VirtAddr::new(base + i as u64 * PAGE_SIZE));
+            unsafe {
+                memory
+                    .mapper
+                    .map_to(page, frame, flags, &mut memory.frame_allocator)?
+                    .flush();
+            }
+        }
+
+        memory.next_mmio = base + len;
+        Ok(VirtAddr::new(base) + (phys - first.start_address()))
+    })
+}
+
+/// `window` cut at level 4 entry boundaries, without the parts
+/// whose entry is in use already, e.g. by the bootloader's stack,
+/// boot info or physical memory mapping
+fn unused_parts(
+    l4: &PageTable,
+    window: Range<u64>,
+) -> impl Iterator<Item = Range<u64>> + Clone + '_ {
+    let first = window.start / L4_SPAN;
+    let last = (window.end - 1) / L4_SPAN;
+    (first..=last)
+        .filter(|&index| l4[index as usize].is_unused())
+        .map(move |index| {
+            (index * L4_SPAN).max(window.start)..((index + 1) * L4_SPAN).min(window.end)
+        })
+}
+
+/// Pick a random page aligned base for `size` bytes in a part of
+/// `window` where nothing is mapped yet
+fn random_base(rng: &mut Rng, l4: &PageTable, window: Range<u64>, size: u64) -> u64 {
+    let slots = |part: &Range<u64>| {
+        (part.end - part.start)
+            .checked_sub(size)
+            .map_or(0, |free| free / PAGE_SIZE + 1)
+    };
+    let parts = unused_parts(l4, window.clone());
+    let total: u64 = parts.clone().map(|part| slots(&part)).sum();
+    assert!(total > 0, "no unused address space in {window:#x?}");
+
+    let mut slot = rng.below(total);
+    for part in parts {
+        let count = slots(&part);
+        if slot < count {
+            return part.start + slot * PAGE_SIZE;
+        }
+        slot -= count;
+    }
+    unreachable!("slot beyond the unused parts of {window:#x?}")
+}
+
+/// Randomise the heap base, user load address and user stack top,
+/// avoiding whatever `mapper` maps already
+///
+/// Must run once before `heap_init` and `user_init`.
+pub fn randomize_layout(mapper: &OffsetPageTable) -> &'static AddressLayout {
+    ADDRESS_LAYOUT.call_once(|| {
+        let mut rng = Rng::from_hardware();
+        let l4 = mapper.level_4_table();
+
+        // leave room below the stack for its guard page
+        let stack_base = random_base(&mut rng, l4, USER_STACK_WINDOW, USER_STACK_SIZE + PAGE_SIZE);
+        let layout = AddressLayout {
+            heap_start: random_base(&mut rng, l4, HEAP_WINDOW, HEAP_SIZE),
+            user_entry: random_base(&mut rng, l4, USER_ENTRY_WINDOW, USER_SIZE),
+            user_stack_top: stack_base + PAGE_SIZE + USER_STACK_SIZE,
+        };
+
+        println!(
+            "layout: heap {:#x}, user image {:#x}, user stack top {:#x}",
+            layout.heap_start, layout.user_entry, layout.user_stack_top
+        );
+        layout
+    })
+}
+
+/// The layout chosen by `randomize_layout`
+pub fn address_layout() -> &'static AddressLayout {
+    ADDRESS_LAYOUT
+        .r#try()
+        .expect("address layout used before randomize_layout")
+}
+
+/// Enable no-execute pages (EFER.NXE) and make ring 0
+/// respect read-only pages (CR0.WP).
+///
+/// Must run before any mapping uses `no_execute()`,
+/// the NX bit is reserved while EFER.NXE is clear.
+pub fn enable_nx_and_wp() {
+    unsafe {
+        if crate::cpu::has_nx() {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// `NO_EXECUTE` when NX is enabled, otherwise empty flags
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Remap the kernel image with per-section protections:
+/// .text read-only, .rodata read-only and non-executable,
+/// .data and .bss writable and non-executable.
+pub fn protect_kernel(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+) -> Result<(), FlagUpdateError> {
+    let sections = [
+        (
+            &raw const __text_start,
+            &raw const __text_end,
+            PageTableFlags::empty(),
+        ),
+        (
+            &raw const __rodata_start,
+            &raw const __rodata_end,
+            no_execute(),
+        ),
+        (
+            &raw const __user_text_start,
+            &raw const __user_text_end,
+            no_execute(),
+        ),
+        (
+            &raw const __data_start,
+            &raw const __data_end,
+            PageTableFlags::WRITABLE | no_execute(),
+        ),
+    ];
+
+    for (start, end, protection) in sections {
+        let start = VirtAddr::from_ptr(start);
+        let end = VirtAddr::from_ptr(end);
+        if start == end {
+            continue;
+        }
+
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end - 1u64),
+        );
+
+        for page in pages {
+            let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address())
+            else {
+                return Err(FlagUpdateError::PageNotMapped);
+            };
+
+            // keep everything but the protection bits as the bootloader set them
+            let flags =
+                (flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE) | protection;
+            unsafe {
+                mapper.update_flags(page, flags)?.flush();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Walk the active page tables and call `f` for every present
+/// leaf entry that is effectively both writable and executable.
+///
+/// A page is writable only when every level has WRITABLE set and
+/// executable only when no level has NO_EXECUTE, so subtrees that
+/// fail either test are skipped.
+unsafe fn for_each_wx_entry(
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(VirtAddr, &mut PageTableEntry),
+) {
+    unsafe fn walk(
+        table: &mut PageTable,
+        level: u8,
+        base: u64,
+        physical_memory_offset: VirtAddr,
+        f: &mut impl FnMut(VirtAddr, &mut PageTableEntry),
+    ) {
+        for (i, entry) in table.iter_mut().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+                || flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                continue;
+            }
+
+            let addr = base | ((i as u64) << (12 + 9 * (level as u64 - 1)));
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                f(VirtAddr::new_truncate(addr), entry);
+                continue;
+            }
+
+            let next: *mut PageTable =
+                (physical_memory_offset + entry.addr().as_u64()).as_mut_ptr();
+            walk(&mut *next, level - 1, addr, physical_memory_offset, f);
+        }
+    }
+
+    let l4 = active_level_4_table(physical_memory_offset);
+    walk(l4, 4, 0, physical_memory_offset, &mut f);
+}
+
+/// Strip execute permission from the writable mappings the
+/// bootloader makes for itself: the identity map of low memory,
+/// of its own code and page tables and of the loaded kernel file,
+/// the boot info page, the kernel stack and the physical memory
+/// window.
+///
+/// Everything else, the kernel's own sections included, is left
+/// for `check_wx` to report.
+///
+/// # Safety
+///
+/// `physical_memory_offset` must be where the bootloader
+/// mapped all of physical memory.
+pub unsafe fn fix_bootloader_wx(physical_memory_offset: VirtAddr, memory_map: &MemoryMap) {
+    let nx = no_execute();
+    if nx.is_empty() {
+        return;
+    }
+
+    let physical_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let window = physical_memory_offset.as_u64()..physical_memory_offset.as_u64() + physical_end;
+    let kernel = VirtAddr::from_ptr(&raw const __text_start).as_u64()
+        ..VirtAddr::from_ptr(&raw const __data_end).as_u64();
+
+    let bootloader_frame = |addr: u64, frame: u64| {
+        memory_map.iter().any(|region| {
+            let range = region.range.start_addr()..region.range.end_addr();
+            range.contains(&frame)
+                && match region.region_type {
+                    MemoryRegionType::Bootloader
+                    | MemoryRegionType::PageTable
+                    | MemoryRegionType::KernelStack
+                    | MemoryRegionType::BootInfo
+                    | MemoryRegionType::FrameZero => true,
+                    // the kernel file as it was loaded, not its sections
+                    MemoryRegionType::Kernel => addr == frame,
+                    _ => false,
+                }
+        })
+    };
+
+    let mut fixed = 0;
+    for_each_wx_entry(physical_memory_offset, |addr, entry| {
+        let addr = addr.as_u64();
+        if kernel.contains(&addr) {
+            return;
+        }
+        if window.contains(&addr)
+            || addr < LOW_MEMORY_END
+            || bootloader_frame(addr, entry.addr().as_u64())
+        {
+            entry.set_flags(entry.flags() | nx);
+            fixed += 1;
+        }
+    });
+    tlb::flush_all();
+    println!("W^X: {fixed} bootloader mappings made non-executable");
+}
+
+/// Panic if any page is mapped both writable and executable
+///
+/// # Safety
+///
+/// `physical_memory_offset` must be where the bootloader
+/// mapped all of physical memory.
+pub unsafe fn check_wx(physical_memory_offset: VirtAddr) {
+    if no_execute().is_empty() {
+        crate::println!("W^X: NX unsupported, skipping check");
+        return;
+    }
+
+    let mut violations = 0;
+    let mut first = None;
+    for_each_wx_entry(physical_memory_offset, |addr, _| {
+        violations += 1;
+        first.get_or_insert(addr);
+    });
+
+    if let Some(addr) = first {
+        panic!("W^X: {violations} writable+executable mappings, first at {addr:?}");
+    }
+}
+
+/// Where the user program and its stack live
+#[derive(Debug, Clone, Copy)]
+pub struct UserLayout {
+    pub image_start: VirtAddr,
+    pub image_end: VirtAddr,
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr, // first address above the stack, stack grows down from here
+    pub stack_bottom: VirtAddr, // lowest mapped stack address
+    pub guard_page: Page,    // left unmapped so an overflow faults
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UserLayoutError {
+    ImageTooLarge,
+    StackOverlapsImage,
+    StackMisaligned,
+    NotUserAccessible(VirtAddr),
+    WritableCode(VirtAddr),
+    ReadOnlyStack(VirtAddr),
+    GuardPageMapped,
+}
+
+impl UserLayout {
+    fn new() -> Self {
+        let image_start = VirtAddr::new(address_layout().user_entry);
+        let stack_top = VirtAddr::new(address_layout().user_stack_top);
+        let stack_bottom = stack_top - USER_STACK_SIZE;
+        let user_main_offset = user_main as *const () as u64 - user_text().as_ptr() as u64;
+
+        Self {
+            image_start,
+            image_end: image_start + USER_SIZE,
+            entry: image_start + user_main_offset,
+            stack_top,
+            stack_bottom,
+            guard_page: Page::containing_address(stack_bottom - 1u64),
+        }
+    }
+
+    fn image_pages(&self) -> PageRangeInclusive {
+        Page::range_inclusive(
+            Page::containing_address(self.image_start),
+            Page::containing_address(self.image_end - 1u64),
+        )
+    }
+
+    fn stack_pages(&self) -> PageRangeInclusive {
+        Page::range_inclusive(
+            Page::containing_address(self.stack_bottom),
+            Page::containing_address(self.stack_top - 1u64),
+        )
+    }
+
+    /// Check the layout is sane and actually mapped the way
+    /// user mode needs it before we iretq into it
+    pub fn validate(&self, mapper: &impl Translate) -> Result<(), UserLayoutError> {
+        if user_text().len() as u64 > self.image_end - self.image_start {
+            return Err(UserLayoutError::ImageTooLarge);
+        }
+        if self.image_end > self.guard_page.start_address() {
+            return Err(UserLayoutError::StackOverlapsImage);
+        }
+        if !self.stack_top.is_aligned(16u64) {
+            return Err(UserLayoutError::StackMisaligned);
+        }
+
+        for page in self.image_pages() {
+            let flags = user_page_flags(mapper, page)?;
+            if flags.contains(PageTableFlags::WRITABLE) {
+                return Err(UserLayoutError::WritableCode(page.start_address()));
+            }
+        }
+
+        for page in self.stack_pages() {
+            let flags = user_page_flags(mapper, page)?;
+            if !flags.contains(PageTableFlags::WRITABLE) {
+                return Err(UserLayoutError::ReadOnlyStack(page.start_address()));
+            }
+        }
+
+        match mapper.translate(self.guard_page.start_address()) {
+            TranslateResult::NotMapped => Ok(()),
+            _ => Err(UserLayoutError::GuardPageMapped),
+        }
+    }
+}
+
+fn user_page_flags(mapper: &impl Translate, page: Page) -> Result<PageTableFlags, UserLayoutError> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { flags, .. }
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) =>
+        {
+            Ok(flags)
+        }
+        _ => Err(UserLayoutError::NotUserAccessible(page.start_address())),
+    }
+}
+
+/// The user program as linked into the kernel image (.user_text)
+fn user_text() -> &'static [u8] {
+    let start = &raw const __user_text_start;
+    let end = &raw const __user_text_end;
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+/// Map each page to a fresh zeroed frame, letting `fill` initialise
+/// it through the physical memory window before it becomes visible.
+/// This way user code never has to be writable, not even briefly.
+fn map_fresh_pages(
+    mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    pages: PageRangeInclusive,
+    flags: PageTableFlags,
+    mut fill: impl FnMut(Page, &mut [u8]),
 ) -> Result<(), MapToError<Size4KiB>> {
-    // Define the virtual address range for the user stack
-    let stack_start = VirtAddr::new(USER_STACK_TOP);  // Address for the top of the user stack
-    let stack_end = stack_start + USER_STACK_SIZE - 1u64; // Address for the bottom of the stack
-    let stack_start_page = Page::containing_address(stack_start);
-    let stack_end_page = Page::containing_address(stack_end);
-    
-    // Generate the page range for the stack
-    let stack_page_range = Page::range_inclusive(stack_start_page, stack_end_page);
-
-    // Map each page in the stack range to a physical frame
-    for page in stack_page_range {
+    for page in pages {
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
-        
+
+        let frame_virt = mapper.phys_offset() + frame.start_address().as_u64();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(frame_virt.as_mut_ptr::<u8>(), page.size() as usize)
+        };
+        bytes.fill(0);
+        fill(page, bytes);
+
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
@@ -51,12 +609,71 @@ pub fn stack_init(
     Ok(())
 }
 
+/// Map the user stack: USER_STACK_SIZE bytes growing down from
+/// the chosen stack top, with the page below left unmapped as a guard
+fn stack_init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    layout: &UserLayout,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | no_execute();
+
+    map_fresh_pages(
+        mapper,
+        frame_allocator,
+        layout.stack_pages(),
+        flags,
+        |_, _| {},
+    )
+}
+
+/// Load the user program at the chosen user entry (read-only, executable)
+fn image_init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    layout: &UserLayout,
+) -> Result<(), MapToError<Size4KiB>> {
+    let text = user_text();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    map_fresh_pages(
+        mapper,
+        frame_allocator,
+        layout.image_pages(),
+        flags,
+        |page, bytes| {
+            let offset = (page.start_address() - layout.image_start) as usize;
+            if offset < text.len() {
+                let len = bytes.len().min(text.len() - offset);
+                bytes[..len].copy_from_slice(&text[offset..offset + len]);
+            }
+        },
+    )
+}
+
+/// Set up user memory: the program image and its stack
+pub fn user_init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<UserLayout, MapToError<Size4KiB>> {
+    let layout = UserLayout::new();
+
+    image_init(mapper, frame_allocator, &layout)?;
+    stack_init(mapper, frame_allocator, &layout)?;
+
+    Ok(layout)
+}
+
 pub fn heap_init(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    let heap_start = address_layout().heap_start;
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_start = VirtAddr::new(heap_start);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -67,26 +684,8 @@ pub fn heap_init(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags: PageTableFlags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
-    }
-
-    let user_page_range = {
-        let user_start = VirtAddr::new(USER_ENTRY);
-        let user_end = user_start + USER_SIZE - 1u64;
-        let user_start_page: Page = Page::containing_address(user_start);
-        let user_end_page: Page = Page::containing_address(user_end);
-        Page::range_inclusive(user_start_page, user_end_page)
-    };
-
-    for page in user_page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        let flags: PageTableFlags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute();
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
@@ -95,11 +694,9 @@ pub fn heap_init(
     unsafe {
         ALLOCATOR
             .lock()
-            .init(HEAP_START as usize, HEAP_SIZE as usize);
+            .init(heap_start as usize, HEAP_SIZE as usize);
     }
 
-    // stack_init(mapper, frame_allocator);
-
     Ok(())
 }
 
@@ -115,6 +712,11 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *ptr
 }
 
+/// Bytes of the heap in use and its total size
+pub fn heap_usage() -> (usize, usize) {
+    (HEAP_USED.load(Ordering::Relaxed), HEAP_SIZE as usize)
+}
+
 pub unsafe fn new_offset_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let l4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(l4_table, physical_memory_offset)
@@ -222,25 +824,31 @@ impl LinkedAllocator {
     }
 }
 
+// Interrupt handlers such as timer callbacks allocate too, so the
+// heap lock is only ever held with interrupts disabled.
 unsafe impl GlobalAlloc for Lock<LinkedAllocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         let (size, align) = LinkedAllocator::size_align(layout);
-        let mut allocator = self.lock();
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let remaining_size = region.end_addr() - alloc_end;
-            if remaining_size > 0 {
-                allocator.add_free_region(alloc_end, remaining_size);
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+                let alloc_end = alloc_start.checked_add(size).expect("overflow");
+                let remaining_size = region.end_addr() - alloc_end;
+                if remaining_size > 0 {
+                    allocator.add_free_region(alloc_end, remaining_size);
+                }
+                HEAP_USED.fetch_add(size, Ordering::Relaxed);
+                alloc_start as *mut u8
+            } else {
+                core::ptr::null_mut()
             }
-            alloc_start as *mut u8
-        } else {
-            core::ptr::null_mut()
-        }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         let (size, _) = LinkedAllocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size);
+        without_interrupts(|| self.lock().add_free_region(ptr as usize, size));
+        HEAP_USED.fetch_sub(size, Ordering::Relaxed);
     }
 }
 
@@ -273,4 +881,4 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         self.next += 1;
         frame
     }
-}
\ No newline at end of file
+}
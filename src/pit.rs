@@ -0,0 +1,102 @@
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the PIT in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43; // write only
+const PORT_B: u16 = 0x61; // bit 0 gates channel 2, bit 1 drives the speaker, bit 5 is its output
+
+// command byte: channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+// command byte: channel 0, lobyte/hibyte access, mode 0, counting waits for a reload value
+const CHANNEL0_STOP: u8 = 0b0011_0000;
+// command byte: latch the channel 0 count
+const CHANNEL0_LATCH: u8 = 0b0000_0000;
+// command byte: channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+pub static PIT: Mutex<Pit> = Mutex::new(Pit::new());
+
+pub struct Pit {
+    channel0: Port<u8>,
+    channel2: Port<u8>,
+    command: Port<u8>,
+    port_b: Port<u8>,
+}
+
+impl Pit {
+    const fn new() -> Self {
+        Self {
+            channel0: Port::new(CHANNEL0),
+            channel2: Port::new(CHANNEL2),
+            command: Port::new(COMMAND),
+            port_b: Port::new(PORT_B),
+        }
+    }
+
+    /// Make channel 0 raise IRQ0 periodically at about `hz`
+    ///
+    /// Returns the divisor in use, the real rate is
+    /// `BASE_FREQUENCY / divisor`.
+    pub fn set_frequency(&mut self, hz: u32) -> u32 {
+        let divisor = divisor_for(hz);
+
+        // a reload value of 0 means 65536
+        let reload = divisor as u16;
+        unsafe {
+            self.command.write(CHANNEL0_RATE_GENERATOR);
+            self.channel0.write(reload as u8);
+            self.channel0.write((reload >> 8) as u8);
+        }
+        divisor
+    }
+
+    /// Stop channel 0 from raising IRQ0
+    pub fn stop(&mut self) {
+        unsafe { self.command.write(CHANNEL0_STOP) };
+    }
+
+    /// The current channel 0 count, counting down from the divisor
+    pub fn read_count(&mut self) -> u16 {
+        unsafe {
+            self.command.write(CHANNEL0_LATCH);
+            let low = self.channel0.read() as u16;
+            let high = self.channel0.read() as u16;
+            high << 8 | low
+        }
+    }
+
+    /// Start channel 2 counting down from `count` with the speaker off
+    ///
+    /// Channel 2 is not wired to an interrupt, poll `channel2_done`.
+    pub fn start_channel2(&mut self, count: u16) {
+        unsafe {
+            let port_b = self.port_b.read();
+            self.port_b.write((port_b & !0b10) | 0b01);
+
+            self.command.write(CHANNEL2_ONESHOT);
+            self.channel2.write(count as u8);
+            self.channel2.write((count >> 8) as u8);
+        }
+    }
+
+    /// Whether channel 2 reached zero since `start_channel2`
+    pub fn channel2_done(&mut self) -> bool {
+        unsafe { self.port_b.read() & (1 << 5) != 0 }
+    }
+}
+
+/// The divisor closest to `hz` that the 16 bit counter can hold,
+/// at least 2 since modes 2 and 3 do not count with a divisor of 1
+pub fn divisor_for(hz: u32) -> u32 {
+    let hz = hz.max(1);
+    ((BASE_FREQUENCY + hz / 2) / hz).clamp(2, 1 << 16)
+}
+
+/// Length of one period of `divisor` input clocks in nanoseconds
+pub fn period_ns(divisor: u32) -> u64 {
+    (divisor as u64 * 1_000_000_000 + BASE_FREQUENCY as u64 / 2) / BASE_FREQUENCY as u64
+}
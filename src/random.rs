@@ -0,0 +1,38 @@
+use x86_64::instructions::random::RdRand;
+
+use crate::cpu;
+
+/// Small non-cryptographic generator (splitmix64) for boot-time
+/// decisions like address space layout randomisation
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Seed from RDRAND when the CPU has it, falling back to the TSC
+    pub fn from_hardware() -> Self {
+        let seed = RdRand::new()
+            .and_then(|rdrand| (0..10).find_map(|_| rdrand.get_u64()))
+            .unwrap_or_else(cpu::rdtsc);
+
+        // mix in the TSC as well so a stuck RDRAND still varies per boot
+        Self::new(seed ^ cpu::rdtsc().rotate_left(32))
+    }
+
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `0..bound`, `bound` must be non-zero
+    pub fn below(&mut self, bound: u64) -> u64 {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+}
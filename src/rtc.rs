@@ -0,0 +1,256 @@
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::cmos::{with_cmos, CMOSRTCRegister};
+use crate::irq::{self, IrqReturn};
+use crate::time::{DateTime, InvalidDateTime};
+use crate::{hpet, println, timezone};
+
+/// ISA line of the real time clock
+pub const RTC_LINE: u8 = 8;
+
+// StatusB interrupt enables
+const PERIODIC_ENABLE: u8 = 1 << 6;
+const ALARM_ENABLE: u8 = 1 << 5;
+const UPDATE_ENDED_ENABLE: u8 = 1 << 4;
+
+// StatusC flags, reading StatusC clears them and lets the RTC interrupt again
+const PERIODIC_FLAG: u8 = 1 << 6;
+const ALARM_FLAG: u8 = 1 << 5;
+const UPDATE_ENDED_FLAG: u8 = 1 << 4;
+
+pub const MIN_RATE: u32 = 2;
+pub const MAX_RATE: u32 = 8192;
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static UPDATES: AtomicU64 = AtomicU64::new(0);
+
+static PERIODIC: Mutex<Option<Box<dyn FnMut() + Send>>> = Mutex::new(None);
+
+// bumped whenever the periodic callback is replaced or removed, so
+// one that was taken out to run is only put back if it is current
+static PERIODIC_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// update interrupts asked for by set_update_interrupt, not just on_next_update
+static UPDATES_WANTED: AtomicBool = AtomicBool::new(false);
+
+struct UpdateCallback {
+    after: u64, // runs once UPDATES passes this
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+static UPDATE: Mutex<Option<UpdateCallback>> = Mutex::new(None);
+
+struct Alarm {
+    at: DateTime,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+static ALARM: Mutex<Option<Alarm>> = Mutex::new(None);
+
+/// The periodic rate is not a power of two between 2 Hz and 8 kHz
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidRate;
+
+/// Take over IRQ8, with every RTC interrupt still disabled
+pub fn init() {
+    with_cmos(|cmos| {
+        let status_b = cmos.read(CMOSRTCRegister::StatusB);
+        let enables = PERIODIC_ENABLE | ALARM_ENABLE | UPDATE_ENDED_ENABLE;
+        cmos.write(CMOSRTCRegister::StatusB, status_b & !enables);
+        cmos.read(CMOSRTCRegister::StatusC);
+    });
+    irq::register(RTC_LINE, "rtc", on_interrupt);
+
+    if hpet::legacy_routing() {
+        println!("RTC: IRQ8 belongs to the HPET, RTC interrupts are unavailable");
+    }
+}
+
+fn on_interrupt() -> IrqReturn {
+    // StatusC flags events whether or not they are enabled, the
+    // enable bits in StatusB are at the same positions
+    let status_c =
+        with_cmos(|cmos| cmos.read(CMOSRTCRegister::StatusC) & cmos.read(CMOSRTCRegister::StatusB));
+    if status_c & (PERIODIC_FLAG | ALARM_FLAG | UPDATE_ENDED_FLAG) == 0 {
+        return IrqReturn::NotMine;
+    }
+
+    if status_c & PERIODIC_FLAG != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+        run_periodic();
+    }
+    if status_c & UPDATE_ENDED_FLAG != 0 {
+        let updates = UPDATES.fetch_add(1, Ordering::Relaxed) + 1;
+        update_ended(updates);
+    }
+    if status_c & ALARM_FLAG != 0 {
+        alarm_fired();
+    }
+
+    IrqReturn::Handled
+}
+
+/// Run the periodic callback outside the lock, so it may replace
+/// or disable itself
+fn run_periodic() {
+    let generation = PERIODIC_GENERATION.load(Ordering::Relaxed);
+    let Some(mut callback) = PERIODIC.lock().take() else {
+        return;
+    };
+
+    callback();
+
+    let mut periodic = PERIODIC.lock();
+    if PERIODIC_GENERATION.load(Ordering::Relaxed) == generation {
+        *periodic = Some(callback);
+    }
+}
+
+fn set_enabled(enable: u8, enabled: bool) {
+    with_cmos(|cmos| {
+        let status_b = cmos.read(CMOSRTCRegister::StatusB);
+        let status_b = if enabled {
+            status_b | enable
+        } else {
+            status_b & !enable
+        };
+        cmos.write(CMOSRTCRegister::StatusB, status_b);
+    });
+}
+
+/// Run `callback` `hz` times a second from IRQ8
+///
+/// `hz` must be a power of two from `MIN_RATE` to `MAX_RATE`. The
+/// callback runs in interrupt context and replaces any earlier one.
+pub fn set_periodic(hz: u32, callback: impl FnMut() + Send + 'static) -> Result<(), InvalidRate> {
+    if !hz.is_power_of_two() || !(MIN_RATE..=MAX_RATE).contains(&hz) {
+        return Err(InvalidRate);
+    }
+
+    // the rate divides the 32768 Hz base clock: hz = 32768 >> (rate - 1)
+    let rate = 16 - hz.trailing_zeros() as u8;
+    with_cmos(|cmos| {
+        let status_a = cmos.read(CMOSRTCRegister::StatusA);
+        cmos.write(CMOSRTCRegister::StatusA, (status_a & 0xF0) | rate);
+    });
+
+    // IRQ8 takes the lock too, an earlier rate may still be running
+    let callback: Box<dyn FnMut() + Send> = Box::new(callback);
+    without_interrupts(|| {
+        PERIODIC_GENERATION.fetch_add(1, Ordering::Relaxed);
+        *PERIODIC.lock() = Some(callback);
+    });
+    set_enabled(PERIODIC_ENABLE, true);
+    Ok(())
+}
+
+pub fn disable_periodic() {
+    set_enabled(PERIODIC_ENABLE, false);
+    without_interrupts(|| {
+        PERIODIC_GENERATION.fetch_add(1, Ordering::Relaxed);
+        PERIODIC.lock().take()
+    });
+}
+
+/// Periodic interrupts received so far
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Raise an interrupt after every RTC update, once a second
+pub fn set_update_interrupt(enabled: bool) {
+    UPDATES_WANTED.store(enabled, Ordering::Relaxed);
+    let pending = without_interrupts(|| UPDATE.lock().is_some());
+    set_enabled(UPDATE_ENDED_ENABLE, enabled || pending);
+}
+
+/// Run `callback` right after an RTC update, when the seconds have
+/// just advanced
+///
+/// The first update interrupt is skipped since its flag may have
+/// been raised long before the interrupt was enabled. Replaces a
+/// callback that has not run yet and runs in interrupt context.
+/// Returns false when IRQ8 is not available for the RTC.
+pub fn on_next_update(callback: impl FnOnce() + Send + 'static) -> bool {
+    if hpet::legacy_routing() {
+        return false;
+    }
+
+    let update = UpdateCallback {
+        after: updates() + 1,
+        callback: Box::new(callback),
+    };
+    without_interrupts(|| *UPDATE.lock() = Some(update));
+    set_enabled(UPDATE_ENDED_ENABLE, true);
+    true
+}
+
+fn update_ended(updates: u64) {
+    let due = UPDATE.lock().take_if(|update| updates > update.after);
+    let Some(update) = due else {
+        return;
+    };
+
+    if !UPDATES_WANTED.load(Ordering::Relaxed) {
+        set_enabled(UPDATE_ENDED_ENABLE, false);
+    }
+    (update.callback)();
+}
+
+/// Update-ended interrupts received so far
+pub fn updates() -> u64 {
+    UPDATES.load(Ordering::Relaxed)
+}
+
+/// Run `callback` once the RTC reaches `at`, in UTC
+///
+/// The RTC alarm only compares hours, minutes and seconds, so it
+/// goes off daily and the date is checked when it does. Replaces
+/// any earlier alarm. The callback runs in interrupt context.
+pub fn set_alarm(
+    at: DateTime,
+    callback: impl FnOnce() + Send + 'static,
+) -> Result<(), InvalidDateTime> {
+    if !at.is_valid() {
+        return Err(InvalidDateTime);
+    }
+
+    // IRQ8 takes the lock too, an earlier alarm may still be armed
+    let alarm = Alarm {
+        at,
+        callback: Box::new(callback),
+    };
+    without_interrupts(|| *ALARM.lock() = Some(alarm));
+
+    with_cmos(|cmos| {
+        let raw_time =
+            timezone::utc_to_rtc(at).to_raw(cmos.is_bcd_mode(), cmos.is_24_hour_format());
+        cmos.write(CMOSRTCRegister::SecondsAlarm, raw_time.second);
+        cmos.write(CMOSRTCRegister::MinutesAlarm, raw_time.minute);
+        cmos.write(CMOSRTCRegister::HoursAlarm, raw_time.hour);
+    });
+    set_enabled(ALARM_ENABLE, true);
+    Ok(())
+}
+
+pub fn cancel_alarm() {
+    set_enabled(ALARM_ENABLE, false);
+    without_interrupts(|| ALARM.lock().take());
+}
+
+fn alarm_fired() {
+    let now = DateTime::now();
+    // taken out of the lock so the callback may set a new alarm
+    let due = ALARM.lock().take_if(|alarm| {
+        (alarm.at.year, alarm.at.month, alarm.at.day) <= (now.year, now.month, now.day)
+    });
+    let Some(alarm) = due else {
+        return; // same time on an earlier day, wait for the next one
+    };
+
+    set_enabled(ALARM_ENABLE, false);
+    (alarm.callback)();
+}
@@ -0,0 +1,213 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::clock::{self, Instant};
+use crate::cmos::with_cmos;
+use crate::time::DateTime;
+use crate::timezone::{self, TimeZone, ZONES};
+use crate::vga_buffer::backspace;
+use crate::wallclock;
+use crate::{print, println};
+
+const PROMPT: &str = "> ";
+
+// keys typed but not yet handled, dropped when the shell falls behind
+const INPUT_LIMIT: usize = 256;
+static INPUT: Mutex<VecDeque<char>> = Mutex::new(VecDeque::new());
+
+type Command = fn(&[&str]);
+
+const COMMANDS: &[(&str, &str, Command)] = &[
+    ("help", "list commands", help),
+    (
+        "date",
+        "print the local time, `-u` in UTC, `+FORMAT` formatted, `-s ISO-8601` sets it",
+        date,
+    ),
+    (
+        "tz",
+        "show the time zone, `tz ZONE|+HH:MM` sets it, `tz list`, `tz rtc local|utc`",
+        tz,
+    ),
+    (
+        "clock",
+        "show the uptime and how the wall clock follows the RTC",
+        clock,
+    ),
+    ("cmos", "show the BIOS drive types and dump the NVRAM", cmos),
+];
+
+/// Queue a key for the shell, called by the keyboard handler
+pub fn push_key(key: char) {
+    let mut input = INPUT.lock();
+    if input.len() < INPUT_LIMIT {
+        input.push_back(key);
+    }
+}
+
+/// Whether keys are waiting for `Shell::poll`
+pub fn has_input() -> bool {
+    without_interrupts(|| !INPUT.lock().is_empty())
+}
+
+fn pop_key() -> Option<char> {
+    without_interrupts(|| INPUT.lock().pop_front())
+}
+
+/// A line based command shell on the VGA console
+pub struct Shell {
+    line: String,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        print!("{PROMPT}");
+        Self {
+            line: String::new(),
+        }
+    }
+
+    /// Handle the keys typed since the last call
+    ///
+    /// Commands run here, outside of interrupt context.
+    pub fn poll(&mut self) {
+        while let Some(key) = pop_key() {
+            match key {
+                '\n' => {
+                    println!();
+                    run(&self.line);
+                    self.line.clear();
+                    print!("{PROMPT}");
+                }
+                '\x08' => {
+                    if self.line.pop().is_some() {
+                        backspace();
+                    }
+                }
+                key if key.is_ascii_graphic() || key == ' ' => {
+                    self.line.push(key);
+                    print!("{key}");
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn run(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(&name) = args.first() else {
+        return;
+    };
+
+    match COMMANDS.iter().find(|(command, _, _)| *command == name) {
+        Some((_, _, command)) => command(&args[1..]),
+        None => println!("unknown command: {name}"),
+    }
+}
+
+fn help(_args: &[&str]) {
+    for (name, description, _) in COMMANDS {
+        println!("{name:8} {description}");
+    }
+}
+
+fn date(args: &[&str]) {
+    match args {
+        [] => println!("{}", DateTime::now().to_local()),
+        ["-u"] => println!("{}", DateTime::now()),
+        ["-s", time @ ..] if !time.is_empty() => match timezone::parse_local(&time.join(" ")) {
+            Ok(new_time) => match new_time.set_system_time() {
+                Ok(()) => println!("{}", DateTime::now().to_local()),
+                Err(_) => println!("date: cannot set {new_time}"),
+            },
+            Err(_) => println!("date: expected YYYY-MM-DD[THH:MM[:SS]][Z|+HH:MM]"),
+        },
+        // the pattern may have been split at spaces, put them back
+        [first, ..] if first.starts_with('+') => {
+            let pattern = args.join(" ");
+            println!("{}", DateTime::now().to_local().format(&pattern[1..]));
+        }
+        _ => println!("usage: date [-u | +FORMAT | -s YYYY-MM-DD[THH:MM:SS]]"),
+    }
+}
+
+fn tz(args: &[&str]) {
+    match args {
+        [] => {
+            let now = DateTime::now().to_local();
+            let rtc = if timezone::rtc_is_local() {
+                "local time"
+            } else {
+                "UTC"
+            };
+            println!(
+                "{} {}, the RTC holds {}",
+                timezone::system(),
+                now.format("%Z (%z)"),
+                rtc
+            );
+        }
+        ["list"] => {
+            for zone in &ZONES {
+                println!("{}", zone.name);
+            }
+        }
+        ["rtc", "local"] => timezone::set_rtc_local(true),
+        ["rtc", "utc"] => timezone::set_rtc_local(false),
+        [zone] => match zone.parse::<TimeZone>() {
+            Ok(zone) => {
+                timezone::set_system(zone);
+                println!("{}", DateTime::now().to_local());
+            }
+            Err(_) => println!("tz: unknown zone {zone}, see `tz list`"),
+        },
+        _ => println!("usage: tz [ZONE | +HH:MM | list | rtc local|utc]"),
+    }
+}
+
+fn clock(_args: &[&str]) {
+    let uptime = Instant::now().since_boot();
+    println!("up {}.{:03} s", uptime.as_secs(), uptime.subsec_millis());
+    println!(
+        "TSC {} kHz, invariant: {}",
+        clock::tsc_khz(),
+        clock::is_invariant()
+    );
+
+    match wallclock::since_epoch() {
+        Some(now) => println!("wall clock {}.{:09}", now.as_secs(), now.subsec_nanos()),
+        None => println!("wall clock not running"),
+    }
+    println!(
+        "last RTC error {} us, {} steps",
+        wallclock::last_error_ns() / 1000,
+        wallclock::steps()
+    );
+}
+
+fn cmos(_args: &[&str]) {
+    let (floppies, disks) = with_cmos(|cmos| (cmos.floppy_types(), cmos.disk_types()));
+    println!("floppy types: {}, {}", floppies.0, floppies.1);
+    println!("disk types: {}, {}", disks.0, disks.1);
+
+    for row in (0..0x80u8).step_by(16) {
+        print!("{row:02x}:");
+        for index in row..row + 16 {
+            match with_cmos(|cmos| cmos.read_nvram(index)) {
+                Some(byte) => print!(" {byte:02x}"),
+                None => print!(" --"),
+            }
+        }
+        println!();
+    }
+}
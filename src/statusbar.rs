@@ -0,0 +1,45 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::clock::Instant;
+use crate::time::DateTime;
+use crate::{mem, task, timer, vga_buffer};
+
+/// How often the status bar is redrawn
+const REFRESH_PERIOD: Duration = Duration::from_millis(250);
+
+/// Who runs on the CPU, a user task or the kernel
+struct Running(Option<u64>);
+
+impl fmt::Display for Running {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(pid) => write!(f, "task {pid}"),
+            None => f.write_str("kernel"),
+        }
+    }
+}
+
+/// Draw the status bar and keep it current from the timer
+pub fn init() {
+    refresh();
+    timer::every(REFRESH_PERIOD, refresh);
+}
+
+/// Redraw with the local time, uptime, heap usage and running task
+pub fn refresh() {
+    let now = DateTime::now().to_local();
+    let uptime = Instant::now().since_boot().as_secs();
+    let (heap_used, heap_size) = mem::heap_usage();
+
+    vga_buffer::set_status(format_args!(
+        " {} | up {}:{:02}:{:02} | heap {}/{} KiB | {}",
+        now.format("%F %T %Z"),
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        heap_used / 1024,
+        heap_size / 1024,
+        Running(task::current_pid()),
+    ));
+}
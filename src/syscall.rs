@@ -0,0 +1,317 @@
+use core::arch::global_asm;
+use core::str;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::gdt::{self, GDT};
+use crate::task::{self, ExitReason};
+use crate::time::DateTime;
+use crate::usercopy::{self, USER_END};
+use crate::{print, wallclock};
+
+// The syscall ABI: the number goes in rax and up to six arguments in
+// rdi, rsi, rdx, r10, r8 and r9, like on Linux. The result comes back
+// in rax, errors as a negated `Errno`. `syscall` clobbers rcx and r11,
+// everything else is preserved. The numbers below never change.
+pub const WRITE: u64 = 0;
+pub const EXIT: u64 = 1;
+pub const YIELD: u64 = 2;
+pub const GETPID: u64 = 3;
+pub const TIME: u64 = 4;
+
+/// Vector of the legacy `int 0x80` gate, same ABI and table but
+/// nothing is clobbered
+pub const INT80_VECTOR: u8 = 0x80;
+
+// console file descriptors accepted by write
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// Error numbers, returned negated in rax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    BadFd = 9,
+    Fault = 14,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, Errno>;
+
+/// Handlers indexed by syscall number
+const TABLE: [Handler; 5] = [sys_write, sys_exit, sys_yield, sys_getpid, sys_time];
+
+/// Registers saved by `syscall_entry`, lowest address first
+#[derive(Debug)]
+#[repr(C)]
+struct SyscallFrame {
+    number: u64,
+    args: [u64; 6], // rdi, rsi, rdx, r10, r8, r9
+    rip: u64,       // from rcx
+    rflags: u64,    // from r11
+    rsp: u64,
+}
+
+/// Registers saved by `int80_entry`, lowest address first, followed
+/// by the interrupt stack frame
+#[derive(Debug)]
+#[repr(C)]
+struct SavedRegisters {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    // pushed by the CPU
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+// top of the TSS privilege stack, where syscall_entry switches to
+static KERNEL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+// the user stack pointer while syscall_entry saves it on the kernel
+// stack, a single scratch slot is enough with one CPU and IF clear
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    /// LSTAR target, entered from ring 3 with interrupts masked
+    fn syscall_entry();
+
+    /// Interrupt gate for `int 0x80`, entered on the TSS privilege stack
+    fn int80_entry();
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    mov [rip + {user_rsp}], rsp",
+    "    mov rsp, [rip + {kernel_rsp}]",
+    "    push qword ptr [rip + {user_rsp}]",
+    "    push r11",
+    "    push rcx",
+    "    push r9",
+    "    push r8",
+    "    push r10",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    call {handler}",
+    "    add rsp, 8", // the number, rax holds the result now
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop r10",
+    "    pop r8",
+    "    pop r9",
+    "    pop rcx",
+    "    pop r11",
+    "    pop rsp",
+    "    sysretq",
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_STACK_TOP,
+    handler = sym syscall_handler,
+);
+
+global_asm!(
+    ".global int80_entry",
+    "int80_entry:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    // unlike syscall through SFMASK, an interrupt gate leaves DF and
+    // AC as ring 3 set them, the kernel needs both clear
+    "    cld",
+    "    cmp byte ptr [rip + {smap}], 0",
+    "    je 2f",
+    "    clac",
+    "2:",
+    "    mov rdi, rsp",
+    "    call {handler}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    iretq",
+    smap = sym usercopy::SMAP_ENABLED,
+    handler = sym int80_handler,
+);
+
+/// Enable `syscall`/`sysret` and point them at `syscall_entry`
+///
+/// Needs the GDT, its selectors are laid out the way STAR expects.
+pub fn init() {
+    let selectors = &GDT.1;
+    KERNEL_STACK_TOP.store(gdt::kernel_stack_top().as_u64(), Ordering::Relaxed);
+
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("GDT order does not suit syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+
+    // enter with interrupts off until the stack is switched, and
+    // with AC clear so SMAP applies to the kernel
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Run system call `number`, the result as it goes back in rax
+pub fn dispatch(number: u64, args: &[u64; 6]) -> u64 {
+    let result = match TABLE.get(number as usize) {
+        Some(handler) => handler(args),
+        None => Err(Errno::NoSys),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) -> u64 {
+    // sysret faults in ring 0 on a non-canonical return address, which
+    // a syscall at the very end of the user half would produce
+    if frame.rip >= USER_END {
+        task::exit_from_syscall(ExitReason::Fault {
+            exception: "syscall at the end of user space",
+            rip: frame.rip,
+            error_code: None,
+            address: None,
+        });
+    }
+
+    dispatch(frame.number, &frame.args)
+}
+
+/// Address of the `int 0x80` entry stub for the IDT
+pub fn int80_entry_addr() -> VirtAddr {
+    VirtAddr::new(int80_entry as *const () as u64)
+}
+
+extern "C" fn int80_handler(regs: &mut SavedRegisters) {
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+    regs.rax = dispatch(regs.rax, &args);
+}
+
+/// write(fd, buf, len): print `len` bytes at `buf` to the console
+///
+/// Fails with `Fault` before printing anything unless every page of
+/// the buffer is mapped for user access.
+fn sys_write(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::BadFd);
+    }
+    let len = usize::try_from(len).map_err(|_| Errno::Invalid)?;
+    usercopy::validate_range(buf, len).map_err(|_| Errno::Fault)?;
+
+    let mut chunk = [0u8; 256];
+    let mut carried = 0;
+    let mut written = 0;
+    while written < len {
+        let count = (chunk.len() - carried).min(len - written);
+        usercopy::copy_from_user(&mut chunk[carried..carried + count], buf + written as u64)
+            .map_err(|_| Errno::Fault)?;
+        written += count;
+
+        let filled = carried + count;
+        carried = 0;
+        for part in chunk[..filled].utf8_chunks() {
+            print!("{}", part.valid());
+
+            // a character cut off at the end of the chunk is
+            // completed by the bytes in the next one
+            let invalid = part.invalid();
+            let cut_off = written < len
+                && str::from_utf8(invalid).is_err_and(|error| error.error_len().is_none());
+            if cut_off {
+                carried = invalid.len();
+            } else if !invalid.is_empty() {
+                print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+        chunk.copy_within(filled - carried..filled, 0);
+    }
+
+    Ok(written as u64)
+}
+
+/// exit(status): end the task, does not return
+fn sys_exit(args: &[u64; 6]) -> Result<u64, Errno> {
+    task::exit_from_syscall(ExitReason::Exited(args[0] as i32));
+}
+
+/// yield(): let interrupts and timers run, returns after the next one
+fn sys_yield(_args: &[u64; 6]) -> Result<u64, Errno> {
+    interrupts::enable_and_hlt();
+    interrupts::disable();
+    Ok(0)
+}
+
+/// getpid(): the pid of the calling task
+fn sys_getpid(_args: &[u64; 6]) -> Result<u64, Errno> {
+    Ok(task::current_pid().unwrap_or(0))
+}
+
+/// time(): nanoseconds since the Unix epoch
+fn sys_time(_args: &[u64; 6]) -> Result<u64, Errno> {
+    let since_epoch = wallclock::since_epoch()
+        .map(|since_epoch| since_epoch.as_nanos() as u64)
+        .unwrap_or_else(|| DateTime::now().to_unix().max(0) as u64 * 1_000_000_000);
+    Ok(since_epoch)
+}
@@ -0,0 +1,171 @@
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::gdt::GDT;
+use crate::mem::UserLayout;
+use crate::println;
+
+/// Why a user task stopped running
+#[derive(Debug, Clone, Copy)]
+pub enum ExitReason {
+    /// The task exited by itself with a status code
+    Exited(i32),
+    /// The task raised an exception and was terminated
+    Fault {
+        exception: &'static str,
+        rip: u64,
+        error_code: Option<u64>,
+        address: Option<u64>, // faulting address for page faults
+    },
+}
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// pid of the task in user mode, 0 while the kernel runs
+static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+
+// kernel stack pointer saved by enter_user, user_return resumes there
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+static EXIT_REASON: Mutex<Option<ExitReason>> = Mutex::new(None);
+
+extern "C" {
+    /// Save the callee-saved registers and stack pointer to
+    /// `kernel_rsp`, then iretq to `entry` in ring 3
+    fn enter_user(entry: u64, user_rsp: u64, user_cs: u64, user_ss: u64, kernel_rsp: *mut u64);
+
+    /// Resumes the kernel after `enter_user` when a task ends, the
+    /// stack pointer must be the one `enter_user` saved
+    fn user_return();
+}
+
+// rdi = entry, rsi = user rsp, rdx = user cs, rcx = user ss, r8 = kernel_rsp
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "    push rbx",
+    "    push rbp",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov [r8], rsp",
+    "    push rcx", // SS
+    "    push rsi", // RSP
+    "    pushfq",   // RFLAGS
+    "    push rdx", // CS
+    "    push rdi", // RIP
+    "    iretq",
+    ".global user_return",
+    "user_return:",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbp",
+    "    pop rbx",
+    "    ret",
+);
+
+pub struct UserTask {
+    pub pid: u64,
+    layout: UserLayout,
+}
+
+impl UserTask {
+    pub fn new(layout: UserLayout) -> Self {
+        Self {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            layout,
+        }
+    }
+
+    /// Switch to user mode and run the task until it ends
+    ///
+    /// The iretq frame is built on the current kernel stack, the
+    /// kernel never touches the user stack itself.
+    pub fn run(&mut self) -> ExitReason {
+        let user_cs = GDT.1.user_code.0 as u64;
+        let user_ds = GDT.1.user_data.0 as u64;
+
+        println!(
+            "task {}: entering user mode at {:#x}",
+            self.pid,
+            self.layout.entry.as_u64()
+        );
+
+        *EXIT_REASON.lock() = None;
+        CURRENT_PID.store(self.pid, Ordering::SeqCst);
+
+        unsafe {
+            enter_user(
+                self.layout.entry.as_u64(),
+                self.layout.stack_top.as_u64(),
+                user_cs,
+                user_ds,
+                KERNEL_RSP.as_ptr(),
+            );
+        }
+
+        CURRENT_PID.store(0, Ordering::SeqCst);
+        EXIT_REASON
+            .lock()
+            .take()
+            .expect("user task returned without an exit reason")
+    }
+}
+
+/// The pid of the task running in user mode, if any
+pub fn current_pid() -> Option<u64> {
+    match CURRENT_PID.load(Ordering::SeqCst) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// End the current user task from a system call
+///
+/// Records `reason` and switches to the kernel stack saved by
+/// `enter_user`, resuming `UserTask::run`. Nothing on the current
+/// stack is dropped, so no locks may be held.
+pub fn exit_from_syscall(reason: ExitReason) -> ! {
+    assert!(current_pid().is_some(), "no user task to end");
+    *EXIT_REASON.lock() = Some(reason);
+
+    let kernel_rsp = KERNEL_RSP.load(Ordering::SeqCst);
+    unsafe {
+        asm!(
+            "mov rsp, {rsp}",
+            "sti",
+            "jmp {resume}",
+            rsp = in(reg) kernel_rsp,
+            resume = in(reg) user_return as *const () as u64,
+            options(noreturn),
+        );
+    }
+}
+
+/// End the current user task from an interrupt or exception handler
+///
+/// Records `reason` and rewrites `stack_frame` so that returning from
+/// the handler resumes the kernel in `UserTask::run` instead of the task.
+pub fn exit_current(stack_frame: &mut InterruptStackFrame, reason: ExitReason) {
+    assert!(current_pid().is_some(), "no user task to end");
+    *EXIT_REASON.lock() = Some(reason);
+
+    let kernel_rsp = KERNEL_RSP.load(Ordering::SeqCst);
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(user_return as *const () as u64);
+            frame.code_segment = GDT.1.code_selector;
+            frame.cpu_flags = RFlags::INTERRUPT_FLAG;
+            frame.stack_pointer = VirtAddr::new(kernel_rsp);
+            frame.stack_segment = SegmentSelector(0);
+        });
+    }
+}
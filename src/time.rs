@@ -1,9 +1,138 @@
-use crate::cmos::{bcd_to_binary, CMOS_INSTANCE};
-use core::u8;
+use crate::acpi;
+use crate::cmos::{bcd_to_binary, binary_to_bcd, with_cmos, RawTime};
+use crate::timezone::{self, LocalTime, UtcOffset};
+use crate::wallclock;
+use core::fmt::{self, Write};
+use core::ops::{Add, Sub};
+use core::str::FromStr;
+use core::time::Duration;
 
-#[derive(Debug)]
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Years before this are taken to be in the next century when
+/// the century is not known, the clock cannot be older than the code
+const BASE_YEAR: u16 = 2025;
+
+/// The date or time is impossible or cannot be stored in the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDateTime;
+
+/// The string is not an ISO 8601 date and time, or names an impossible one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseDateTimeError;
+
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400)
+}
+
+/// Number of days in `month` (1-12) of `year`
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+///
+/// Counts in 400 year eras of 146097 days, with years starting
+/// in March so the leap day is the last day of its year.
+pub(crate) fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of `days_from_civil`, (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+    Monday = 1,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// ISO 8601 number, Monday is 1 and Sunday 7
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Weekday::Monday => "Monday",
+            Weekday::Tuesday => "Tuesday",
+            Weekday::Wednesday => "Wednesday",
+            Weekday::Thursday => "Thursday",
+            Weekday::Friday => "Friday",
+            Weekday::Saturday => "Saturday",
+            Weekday::Sunday => "Sunday",
+        }
+    }
+
+    /// The weekday of a day counted from 1970-01-01, a Thursday
+    pub(crate) fn from_days(days: i64) -> Self {
+        match days.rem_euclid(7) {
+            0 => Weekday::Thursday,
+            1 => Weekday::Friday,
+            2 => Weekday::Saturday,
+            3 => Weekday::Sunday,
+            4 => Weekday::Monday,
+            5 => Weekday::Tuesday,
+            _ => Weekday::Wednesday,
+        }
+    }
+}
+
+/// A UTC date and time with second resolution
+///
+/// Fields are ordered from most to least significant, so the derived
+/// ordering is chronological for valid values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DateTime {
-    pub year: u8,
+    pub year: u16,
     pub month: u8,
     pub day: u8,
     pub hour: u8,
@@ -12,7 +141,7 @@ pub struct DateTime {
 }
 
 impl DateTime {
-    pub fn new(year: u8, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Self {
         Self {
             year,
             month,
@@ -23,22 +152,480 @@ impl DateTime {
         }
     }
 
+    /// Like `new`, but rejects impossible dates and times
+    pub fn try_new(
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+    ) -> Result<Self, InvalidDateTime> {
+        let time = Self::new(year, month, day, hour, minute, second);
+        if time.is_valid() {
+            Ok(time)
+        } else {
+            Err(InvalidDateTime)
+        }
+    }
+
+    /// The current time from the wall clock, or the RTC until the
+    /// wall clock runs
     pub fn now() -> Self {
+        wallclock::since_epoch()
+            .and_then(|since_epoch| Self::from_unix(since_epoch.as_secs() as i64))
+            .unwrap_or_else(Self::read_rtc)
+    }
+
+    /// The current time from the RTC, in UTC even when the RTC
+    /// holds local time
+    pub fn read_rtc() -> Self {
+        let century_register = acpi::century_register();
+
         // read time from CMOS
-        let mut raw_time = unsafe { CMOS_INSTANCE.time_now() };
+        let (raw_time, bcd, hours_24) = with_cmos(|cmos| {
+            (
+                cmos.time_now(century_register),
+                cmos.is_bcd_mode(),
+                cmos.is_24_hour_format(),
+            )
+        });
+
+        timezone::rtc_to_utc(Self::from_raw(raw_time, bcd, hours_24))
+    }
 
-        // if cmos isnt in bcd mode than just return as is
-        if !unsafe { CMOS_INSTANCE.is_bcd_mode() } {
-            return raw_time;
+    /// Whether every field is in range for its month and year
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC, negative before it
+    pub fn to_unix(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    /// The date and time `seconds` after the Unix epoch, `None`
+    /// when the year does not fit
+    pub fn from_unix(seconds: i64) -> Option<Self> {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let second_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+
+        Some(Self::new(
+            u16::try_from(year).ok()?,
+            month,
+            day,
+            (second_of_day / 3600) as u8,
+            (second_of_day / 60 % 60) as u8,
+            (second_of_day % 60) as u8,
+        ))
+    }
+
+    pub fn weekday(&self) -> Weekday {
+        Weekday::from_days(days_from_civil(self.year as i64, self.month, self.day))
+    }
+
+    /// Day of the year, 1 for January 1st
+    pub fn day_of_year(&self) -> u16 {
+        let days_before: u16 = (1..self.month)
+            .map(|month| days_in_month(self.year, month) as u16)
+            .sum();
+        days_before + self.day as u16
+    }
+
+    /// `seconds` later, or earlier when negative
+    pub fn checked_add_seconds(&self, seconds: i64) -> Option<Self> {
+        Self::from_unix(self.to_unix().checked_add(seconds)?)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        let seconds = i64::try_from(duration.as_secs()).ok()?;
+        Self::from_unix(self.to_unix().checked_add(seconds)?)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        let seconds = i64::try_from(duration.as_secs()).ok()?;
+        Self::from_unix(self.to_unix().checked_sub(seconds)?)
+    }
+
+    /// Time from `earlier` to `self`, `None` if `earlier` is later
+    pub fn duration_since(&self, earlier: DateTime) -> Option<Duration> {
+        let seconds = self.to_unix() - earlier.to_unix();
+        u64::try_from(seconds).ok().map(Duration::from_secs)
+    }
+
+    /// This UTC time in the system time zone
+    pub fn to_local(self) -> LocalTime {
+        timezone::system().to_local(self)
+    }
+
+    /// Format with a strftime-like `pattern`, for use with `{}`
+    ///
+    /// Supports `%Y %C %y %m %d %e %j %H %I %M %S %p %a %A %b %h %B
+    /// %u %w %s %F %T %D %R %z %Z %n %t %%`, anything else is copied
+    /// as is.
+    pub fn format<'a>(&self, pattern: &'a str) -> Formatted<'a> {
+        Formatted {
+            time: *self,
+            pattern,
+            offset: UtcOffset::UTC,
+            zone: "UTC",
+        }
+    }
+
+    /// Set the RTC and the wall clock to this UTC date and time
+    ///
+    /// Without a century register only the hundred years from
+    /// `BASE_YEAR` can be stored, `now` could not read others back.
+    pub fn set_system_time(&self) -> Result<(), InvalidDateTime> {
+        if !self.is_valid() {
+            return Err(InvalidDateTime);
         }
 
-        // convert each file from bcd to binary
-        raw_time.second = bcd_to_binary(raw_time.second);
-        raw_time.minute = bcd_to_binary(raw_time.minute);
-        raw_time.hour = bcd_to_binary(raw_time.hour);
-        raw_time.day = bcd_to_binary(raw_time.day);
-        raw_time.month = bcd_to_binary(raw_time.month);
-        raw_time.year = bcd_to_binary(raw_time.year);
-        raw_time
+        let rtc_time = timezone::utc_to_rtc(*self);
+        let century_register = acpi::century_register();
+        let representable = match century_register {
+            Some(_) => rtc_time.year <= 9999,
+            None => (BASE_YEAR..BASE_YEAR + 100).contains(&rtc_time.year),
+        };
+        if !representable {
+            return Err(InvalidDateTime);
+        }
+
+        with_cmos(|cmos| {
+            let raw_time = rtc_time.to_raw(cmos.is_bcd_mode(), cmos.is_24_hour_format());
+            cmos.set_time(raw_time, century_register);
+        });
+        wallclock::set(*self);
+
+        Ok(())
+    }
+
+    /// Convert to CMOS register values in the given modes
+    pub(crate) fn to_raw(self, bcd: bool, hours_24: bool) -> RawTime {
+        let convert = |value: u8| if bcd { binary_to_bcd(value) } else { value };
+
+        let hour = if hours_24 {
+            convert(self.hour)
+        } else {
+            // 0 is 12 AM, 12 is 12 PM
+            let pm = if self.hour >= 12 { 0x80 } else { 0 };
+            let hour = match self.hour % 12 {
+                0 => 12,
+                hour => hour,
+            };
+            convert(hour) | pm
+        };
+
+        RawTime {
+            second: convert(self.second),
+            minute: convert(self.minute),
+            hour,
+            day: convert(self.day),
+            month: convert(self.month),
+            year: convert((self.year % 100) as u8),
+            century: Some(convert((self.year / 100) as u8)),
+        }
+    }
+
+    /// Convert the CMOS register values to a 24 hour time with a full year
+    fn from_raw(raw_time: RawTime, bcd: bool, hours_24: bool) -> Self {
+        let convert = |value: u8| if bcd { bcd_to_binary(value) } else { value };
+
+        // the PM flag is the top bit of the hour in either mode
+        let mut hour = convert(raw_time.hour & 0x7F);
+        if !hours_24 {
+            let pm = raw_time.hour & 0x80 != 0;
+            hour %= 12; // 12 AM is midnight, 12 PM noon
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = convert(raw_time.year) as u16;
+        let year = match raw_time.century {
+            Some(century) => convert(century) as u16 * 100 + year,
+            None => {
+                let year = BASE_YEAR / 100 * 100 + year;
+                if year < BASE_YEAR {
+                    year + 100
+                } else {
+                    year
+                }
+            }
+        };
+
+        Self::new(
+            year,
+            convert(raw_time.month),
+            convert(raw_time.day),
+            hour,
+            convert(raw_time.minute),
+            convert(raw_time.second),
+        )
+    }
+}
+
+/// Sub-second parts of the duration are dropped
+impl Add<Duration> for DateTime {
+    type Output = DateTime;
+
+    fn add(self, duration: Duration) -> DateTime {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to date")
+    }
+}
+
+/// Sub-second parts of the duration are dropped
+impl Sub<Duration> for DateTime {
+    type Output = DateTime;
+
+    fn sub(self, duration: Duration) -> DateTime {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from date")
+    }
+}
+
+/// ISO 8601 in UTC, `2025-01-31T23:59:59Z`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// A `DateTime` with a strftime-like pattern, from `DateTime::format`
+#[derive(Debug, Clone, Copy)]
+pub struct Formatted<'a> {
+    time: DateTime,
+    pattern: &'a str,
+    offset: UtcOffset,
+    zone: &'static str,
+}
+
+impl Formatted<'_> {
+    /// Report `time` as being `offset` from UTC in `zone` for `%z`,
+    /// `%Z` and `%s`, an empty `zone` prints the offset instead
+    pub(crate) fn in_zone(self, offset: UtcOffset, zone: &'static str) -> Self {
+        Self {
+            offset,
+            zone,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = &self.time;
+        let month_name = MONTH_NAMES
+            .get((time.month as usize).wrapping_sub(1))
+            .copied()
+            .unwrap_or("???");
+        let hour_12 = match time.hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+
+        let mut chars = self.pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                f.write_char(c)?;
+                continue;
+            }
+
+            match chars.next() {
+                Some('Y') => write!(f, "{:04}", time.year)?,
+                Some('C') => write!(f, "{:02}", time.year / 100)?,
+                Some('y') => write!(f, "{:02}", time.year % 100)?,
+                Some('m') => write!(f, "{:02}", time.month)?,
+                Some('d') => write!(f, "{:02}", time.day)?,
+                Some('e') => write!(f, "{:2}", time.day)?,
+                Some('j') => write!(f, "{:03}", time.day_of_year())?,
+                Some('H') => write!(f, "{:02}", time.hour)?,
+                Some('I') => write!(f, "{:02}", hour_12)?,
+                Some('M') => write!(f, "{:02}", time.minute)?,
+                Some('S') => write!(f, "{:02}", time.second)?,
+                Some('p') => f.write_str(if time.hour < 12 { "AM" } else { "PM" })?,
+                Some('a') => f.write_str(&time.weekday().name()[..3])?,
+                Some('A') => f.write_str(time.weekday().name())?,
+                Some('b' | 'h') => f.write_str(&month_name[..3])?,
+                Some('B') => f.write_str(month_name)?,
+                Some('u') => write!(f, "{}", time.weekday().number())?,
+                Some('w') => write!(f, "{}", time.weekday().number() % 7)?,
+                // the fields are local time, the epoch is not
+                Some('s') => write!(f, "{}", time.to_unix() - self.offset.seconds() as i64)?,
+                Some('F') => write!(f, "{:04}-{:02}-{:02}", time.year, time.month, time.day)?,
+                Some('T') => write!(f, "{:02}:{:02}:{:02}", time.hour, time.minute, time.second)?,
+                Some('D') => write!(
+                    f,
+                    "{:02}/{:02}/{:02}",
+                    time.month,
+                    time.day,
+                    time.year % 100
+                )?,
+                Some('R') => write!(f, "{:02}:{:02}", time.hour, time.minute)?,
+                Some('z') => {
+                    let minutes = self.offset.seconds().abs() / 60;
+                    let sign = if self.offset.seconds() < 0 { '-' } else { '+' };
+                    write!(f, "{}{:02}{:02}", sign, minutes / 60, minutes % 60)?
+                }
+                Some('Z') if self.zone.is_empty() => write!(f, "{}", self.offset)?,
+                Some('Z') => f.write_str(self.zone)?,
+                Some('n') => f.write_char('\n')?,
+                Some('t') => f.write_char('\t')?,
+                Some('%') => f.write_char('%')?,
+                Some(other) => {
+                    f.write_char('%')?;
+                    f.write_char(other)?;
+                }
+                None => f.write_char('%')?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a string byte by byte for `DateTime::from_str`
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.at).copied()
+    }
+
+    /// Skip `byte` if it is next
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ParseDateTimeError> {
+        self.eat(byte).then_some(()).ok_or(ParseDateTimeError)
+    }
+
+    /// A number of exactly `count` decimal digits
+    fn digits(&mut self, count: usize) -> Result<u16, ParseDateTimeError> {
+        let mut value = 0;
+        for _ in 0..count {
+            match self.peek() {
+                Some(digit @ b'0'..=b'9') => value = value * 10 + (digit - b'0') as u16,
+                _ => return Err(ParseDateTimeError),
+            }
+            self.at += 1;
+        }
+        Ok(value)
+    }
+
+    /// A UTC offset, `Z`, `+HH:MM`, `-HHMM` or `+HH`
+    fn utc_offset(&mut self) -> Result<UtcOffset, ParseDateTimeError> {
+        let sign = match self.peek() {
+            Some(b'Z' | b'z') => {
+                self.at += 1;
+                return Ok(UtcOffset::UTC);
+            }
+            Some(b'+') => 1,
+            Some(b'-') => -1,
+            _ => return Err(ParseDateTimeError),
+        };
+        self.at += 1;
+
+        let hours = self.digits(2)?;
+        let minutes = if self.eat(b':') || self.peek().is_some() {
+            self.digits(2)?
+        } else {
+            0
+        };
+        if hours > 23 || minutes > 59 {
+            return Err(ParseDateTimeError);
+        }
+        UtcOffset::from_seconds(sign * (hours as i32 * 3600 + minutes as i32 * 60))
+            .ok_or(ParseDateTimeError)
+    }
+}
+
+/// Parse a UTC offset on its own, as accepted after an ISO 8601 time
+pub(crate) fn parse_utc_offset(s: &str) -> Result<UtcOffset, ParseDateTimeError> {
+    let mut cursor = Cursor {
+        bytes: s.as_bytes(),
+        at: 0,
+    };
+    let offset = cursor.utc_offset()?;
+    match cursor.peek() {
+        None => Ok(offset),
+        Some(_) => Err(ParseDateTimeError),
+    }
+}
+
+/// Parse ISO 8601 into the date and time as written and its UTC
+/// offset, `None` when it has none
+///
+/// Takes `YYYY-MM-DD`, optionally followed by `T` or a space and
+/// `HH:MM[:SS[.fraction]]` with a `Z` or `±HH[:MM]` UTC offset.
+/// Fractions of a second are dropped.
+pub(crate) fn parse_iso(s: &str) -> Result<(DateTime, Option<UtcOffset>), ParseDateTimeError> {
+    let mut cursor = Cursor {
+        bytes: s.as_bytes(),
+        at: 0,
+    };
+
+    let year = cursor.digits(4)?;
+    cursor.expect(b'-')?;
+    let month = cursor.digits(2)? as u8;
+    cursor.expect(b'-')?;
+    let day = cursor.digits(2)? as u8;
+
+    let (mut hour, mut minute, mut second, mut offset) = (0, 0, 0, None);
+    if cursor.eat(b'T') || cursor.eat(b't') || cursor.eat(b' ') {
+        hour = cursor.digits(2)? as u8;
+        cursor.expect(b':')?;
+        minute = cursor.digits(2)? as u8;
+        if cursor.eat(b':') {
+            second = cursor.digits(2)? as u8;
+            if cursor.eat(b'.') || cursor.eat(b',') {
+                cursor.digits(1)?;
+                while cursor.digits(1).is_ok() {}
+            }
+        }
+        if cursor.peek().is_some() {
+            offset = Some(cursor.utc_offset()?);
+        }
+    }
+    if cursor.peek().is_some() {
+        return Err(ParseDateTimeError);
+    }
+
+    let time = DateTime::try_new(year, month, day, hour, minute, second)
+        .map_err(|_| ParseDateTimeError)?;
+    Ok((time, offset))
+}
+
+/// Parse ISO 8601 as described for `parse_iso`, the result is
+/// converted to UTC and a time without an offset is taken as UTC
+impl FromStr for DateTime {
+    type Err = ParseDateTimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (time, offset) = parse_iso(s)?;
+        let offset = offset.unwrap_or(UtcOffset::UTC);
+        time.checked_add_seconds(-offset.seconds() as i64)
+            .ok_or(ParseDateTimeError)
     }
 }
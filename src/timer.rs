@@ -0,0 +1,370 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::clock::Instant;
+use crate::irq::{self, IrqReturn};
+use crate::pit::{self, PIT};
+use crate::{apic, hpet, println};
+
+/// Tick rate used unless something else is asked for
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+// length of the current tick period
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+// monotonic clock reading at the last tick
+static LAST_TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+// whether set_frequency got the HPET to drive the tick, not the PIT
+static HPET_TICK: AtomicBool = AtomicBool::new(false);
+
+/// Start the periodic tick at `hz` on IRQ0, driven by the
+/// HPET when there is one and PIT channel 0 otherwise
+pub fn init(hz: u32) {
+    let hz = set_frequency(hz);
+    irq::register(irq::TIMER, "timer", on_tick);
+
+    let source = if HPET_TICK.load(Ordering::Relaxed) {
+        "HPET"
+    } else {
+        "PIT"
+    };
+    println!("timer: {} Hz from the {}", hz, source);
+}
+
+/// Change the tick rate, returns the rate actually programmed
+///
+/// Uptime stays continuous, ticks counted so far keep
+/// the length they had.
+pub fn set_frequency(hz: u32) -> u32 {
+    without_interrupts(|| {
+        let hpet_period = hpet::start_periodic(hz);
+        let hpet_tick = hpet_period.is_some();
+        let tick_ns = match hpet_period {
+            Some(tick_ns) => {
+                // both would raise IRQ0
+                PIT.lock().stop();
+                tick_ns
+            }
+            None => pit::period_ns(PIT.lock().set_frequency(hz)),
+        }
+        .max(1);
+
+        HPET_TICK.store(hpet_tick, Ordering::Relaxed);
+        TICK_NS.store(tick_ns, Ordering::Relaxed);
+        (1_000_000_000 / tick_ns) as u32
+    })
+}
+
+fn on_tick() -> IrqReturn {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    UPTIME_NS.fetch_add(TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+    LAST_TICK_NS.store(clock_ns(), Ordering::Relaxed);
+    run_timers(now);
+    IrqReturn::Handled
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the tick was started, with tick resolution
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NS.load(Ordering::Relaxed))
+}
+
+// The timer wheel has WHEEL_LEVELS levels of WHEEL_SIZE slots. Level n
+// holds timers due in less than WHEEL_SIZE^(n + 1) ticks, in the slot
+// given by bits [6n, 6n + 6) of their expiry tick. Whenever the lower
+// level wraps around, the matching slot of the level above is cascaded
+// down, so insertion and expiry are O(1) per timer.
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SIZE: usize = 1 << WHEEL_BITS;
+const WHEEL_LEVELS: usize = 4;
+const WHEEL_SPAN: u64 = 1 << (WHEEL_BITS * WHEEL_LEVELS as u32);
+
+struct Timer {
+    expires: u64,
+    period: u64, // in ticks, 0 for one-shot timers
+    cancelled: Arc<AtomicBool>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+struct Wheel {
+    now: u64, // last tick the wheel has run
+    len: usize,
+    slots: [[Vec<Timer>; WHEEL_SIZE]; WHEEL_LEVELS],
+}
+
+impl Wheel {
+    fn insert(&mut self, mut timer: Timer) {
+        self.len += 1;
+        timer.expires = timer.expires.max(self.now + 1);
+        let delta = timer.expires - self.now;
+
+        let level = (0..WHEEL_LEVELS)
+            .find(|&level| delta < 1 << (WHEEL_BITS * (level as u32 + 1)))
+            .unwrap_or(WHEEL_LEVELS - 1);
+
+        // too far out for the wheel: park it in the last slot,
+        // it is put back in place when that slot cascades
+        let position = timer.expires.min(self.now + WHEEL_SPAN - 1);
+        let slot = (position >> (WHEEL_BITS * level as u32)) as usize % WHEEL_SIZE;
+        self.slots[level][slot].push(timer);
+    }
+
+    /// Advance to `tick` and return the timers due at it
+    fn advance(&mut self, tick: u64) -> Vec<Timer> {
+        self.now = tick;
+        let mut due = Vec::new();
+
+        for level in 1..WHEEL_LEVELS {
+            let below = tick >> (WHEEL_BITS * level as u32);
+            if tick & ((1 << (WHEEL_BITS * level as u32)) - 1) != 0 {
+                break;
+            }
+
+            let slot = below as usize % WHEEL_SIZE;
+            let cascaded = mem::take(&mut self.slots[level][slot]);
+            self.len -= cascaded.len();
+            for timer in cascaded {
+                // inserting would push a timer due now to the next tick
+                if timer.expires <= tick {
+                    due.push(timer);
+                } else {
+                    self.insert(timer);
+                }
+            }
+        }
+
+        let slot = mem::take(&mut self.slots[0][tick as usize % WHEEL_SIZE]);
+        self.len -= slot.len();
+        due.extend(slot);
+        due
+    }
+
+    /// The earliest tick a live timer expires at
+    fn next_expiry(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|timer| !timer.cancelled.load(Ordering::Relaxed))
+            .map(|timer| timer.expires)
+            .min()
+    }
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    now: 0,
+    len: 0,
+    slots: [const { [const { Vec::new() }; WHEEL_SIZE] }; WHEEL_LEVELS],
+});
+
+/// Run the callbacks of every timer due up to `now`
+///
+/// Callbacks run in interrupt context without the wheel locked, so
+/// they may add or cancel timers but must not block.
+fn run_timers(now: u64) {
+    loop {
+        let due = {
+            let mut wheel = WHEEL.lock();
+            if wheel.now >= now {
+                break;
+            }
+            if wheel.len == 0 {
+                wheel.now = now;
+                break;
+            }
+            let tick = wheel.now + 1;
+            wheel.advance(tick)
+        };
+
+        for mut timer in due {
+            if timer.cancelled.load(Ordering::Acquire) {
+                continue;
+            }
+            (timer.callback)();
+
+            if timer.period != 0 && !timer.cancelled.load(Ordering::Acquire) {
+                timer.expires += timer.period;
+                WHEEL.lock().insert(timer);
+            }
+        }
+    }
+}
+
+/// Ticks that cover at least `duration`
+///
+/// One extra tick accounts for the part of the current
+/// tick that has already passed.
+fn ticks_for(duration: Duration) -> u64 {
+    let tick_ns = TICK_NS.load(Ordering::Relaxed).max(1) as u128;
+    let ticks = duration.as_nanos().div_ceil(tick_ns) + 1;
+    ticks.min(u64::MAX as u128 / 2) as u64
+}
+
+fn add_timer(delay: u64, period: u64, callback: Box<dyn FnMut() + Send>) -> TimerHandle {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let timer = Timer {
+        expires: ticks() + delay,
+        period,
+        cancelled: cancelled.clone(),
+        callback,
+    };
+    without_interrupts(|| WHEEL.lock().insert(timer));
+
+    TimerHandle { cancelled }
+}
+
+/// A pending timer, dropping the handle does not cancel it
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    /// Stop the timer, its callback does not run afterwards
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
+/// Run `callback` once after `delay`
+pub fn after(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerHandle {
+    let mut callback = Some(callback);
+    add_timer(
+        ticks_for(delay),
+        0,
+        Box::new(move || {
+            if let Some(callback) = callback.take() {
+                callback();
+            }
+        }),
+    )
+}
+
+/// Run `callback` every `period`, starting one period from now
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerHandle {
+    let period = ticks_for(period).saturating_sub(1).max(1);
+    add_timer(period, period, Box::new(callback))
+}
+
+/// Returned by `wait_timeout` when the condition did not become true
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Halt until `duration` has passed
+///
+/// Must be called with interrupts enabled, the timer tick wakes it.
+pub fn sleep(duration: Duration) {
+    let _ = wait_timeout(duration, || false);
+}
+
+/// Halt until `done` returns true or `timeout` passes
+///
+/// `done` is checked with interrupts disabled after every interrupt,
+/// so a wakeup from an interrupt handler is never missed.
+pub fn wait_timeout(timeout: Duration, mut done: impl FnMut() -> bool) -> Result<(), TimedOut> {
+    assert!(
+        interrupts::are_enabled(),
+        "waiting with interrupts disabled"
+    );
+
+    let expired = Arc::new(AtomicBool::new(false));
+    let flag = expired.clone();
+    let timer = after(timeout, move || flag.store(true, Ordering::Release));
+
+    let result = loop {
+        interrupts::disable();
+        if done() {
+            timer.cancel();
+            break Ok(());
+        }
+        if expired.load(Ordering::Acquire) {
+            break Err(TimedOut);
+        }
+        // sti; hlt so an interrupt between the checks and the hlt still wakes us
+        interrupts::enable_and_hlt();
+    };
+
+    interrupts::enable();
+    result
+}
+
+fn clock_ns() -> u64 {
+    Instant::now().since_boot().as_nanos() as u64
+}
+
+/// Halt until the next interrupt, unless `ready` says there is work
+///
+/// `ready` is checked with interrupts disabled, so work queued by an
+/// interrupt handler right before halting is not missed.
+///
+/// With the APIC timer available the periodic tick is masked while
+/// halted and the CPU only wakes up for the next pending timer or a
+/// device interrupt. The ticks that were skipped are accounted for
+/// on wakeup. Must be called with interrupts enabled.
+pub fn idle(mut ready: impl FnMut() -> bool) {
+    interrupts::disable();
+    if ready() {
+        interrupts::enable();
+        return;
+    }
+    if !apic::timer_ready() {
+        interrupts::enable_and_hlt();
+        return;
+    }
+
+    let next = WHEEL.lock().next_expiry();
+    let tick_ns = TICK_NS.load(Ordering::Relaxed).max(1);
+
+    if let Some(expires) = next {
+        let ticks_left = expires.saturating_sub(ticks());
+        if ticks_left <= 1 {
+            // due with the next tick anyway
+            interrupts::enable_and_hlt();
+            return;
+        }
+
+        // far off timers would overflow, wake up early for them instead
+        let deadline = LAST_TICK_NS
+            .load(Ordering::Relaxed)
+            .saturating_add(ticks_left.saturating_mul(tick_ns));
+        let wait = Duration::from_nanos(deadline.saturating_sub(clock_ns()));
+        apic::arm_timer(wait.min(apic::max_timer_delay()));
+    }
+
+    irq::mask(irq::TIMER);
+    interrupts::enable_and_hlt();
+    interrupts::disable();
+
+    apic::stop_timer();
+    irq::unmask(irq::TIMER);
+    catch_up(tick_ns);
+    interrupts::enable();
+}
+
+/// Count the ticks that passed while the tick was masked
+fn catch_up(tick_ns: u64) {
+    let last = LAST_TICK_NS.load(Ordering::Relaxed);
+    let missed = clock_ns().saturating_sub(last) / tick_ns;
+    if missed == 0 {
+        return;
+    }
+
+    LAST_TICK_NS.store(last + missed * tick_ns, Ordering::Relaxed);
+    UPTIME_NS.fetch_add(missed * tick_ns, Ordering::Relaxed);
+    let now = TICKS.fetch_add(missed, Ordering::Relaxed) + missed;
+    run_timers(now);
+}
@@ -0,0 +1,431 @@
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::time::{
+    days_from_civil, days_in_month, parse_iso, parse_utc_offset, DateTime, Formatted,
+    ParseDateTimeError, Weekday,
+};
+use crate::{cmdline, println};
+
+/// Not a known zone name or UTC offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTimeZone;
+
+/// A fixed difference from UTC, positive east of Greenwich
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UtcOffset {
+    seconds: i32,
+}
+
+impl UtcOffset {
+    pub const UTC: UtcOffset = UtcOffset { seconds: 0 };
+
+    /// `None` unless strictly within a day of UTC
+    pub const fn from_seconds(seconds: i32) -> Option<Self> {
+        if seconds > -86_400 && seconds < 86_400 {
+            Some(Self { seconds })
+        } else {
+            None
+        }
+    }
+
+    pub const fn seconds(self) -> i32 {
+        self.seconds
+    }
+}
+
+/// `+HH:MM`
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.seconds < 0 { '-' } else { '+' };
+        let minutes = self.seconds.abs() / 60;
+        write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+/// `UTC`, `Z` or an ISO 8601 offset such as `+05:30` or `-0800`
+impl FromStr for UtcOffset {
+    type Err = InvalidTimeZone;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("UTC") || s.eq_ignore_ascii_case("GMT") {
+            return Ok(UtcOffset::UTC);
+        }
+        parse_utc_offset(s).map_err(|_| InvalidTimeZone)
+    }
+}
+
+/// A DST change on the `week`th `weekday` of `month`, week 5 being
+/// the last one, at `time` seconds past midnight
+///
+/// `time` is UTC when `utc` is set, otherwise the wall clock time
+/// in effect just before the change.
+#[derive(Debug, PartialEq, Eq)]
+struct Transition {
+    month: u8,
+    week: u8,
+    weekday: Weekday,
+    time: i32,
+    utc: bool,
+}
+
+impl Transition {
+    /// Day of the month the change happens in `year`
+    fn day(&self, year: u16) -> u8 {
+        let first = days_from_civil(year as i64, self.month, 1);
+        let first_weekday = Weekday::from_days(first).number();
+        let mut day = 1 + (7 + self.weekday.number() - first_weekday) % 7 + (self.week - 1) * 7;
+        while day > days_in_month(year, self.month) {
+            day -= 7;
+        }
+        day
+    }
+
+    /// Unix time of the change in `year`, `offset` being the wall
+    /// clock offset before it
+    fn instant(&self, year: u16, offset: i32) -> i64 {
+        let days = days_from_civil(year as i64, self.month, self.day(year));
+        let wall = days * 86_400 + self.time as i64;
+        if self.utc {
+            wall
+        } else {
+            wall - offset as i64
+        }
+    }
+}
+
+/// When daylight saving time starts and ends, and by how much it
+/// moves the clock
+#[derive(Debug, PartialEq, Eq)]
+pub struct DstRule {
+    start: Transition,
+    end: Transition,
+    save: i32,
+}
+
+impl DstRule {
+    /// Whether DST is in effect at Unix time `unix` for a zone
+    /// `standard` seconds from UTC
+    ///
+    /// No rule changes near New Year, so the UTC year is used.
+    fn in_effect(&self, unix: i64, year: u16, standard: i32) -> bool {
+        let start = self.start.instant(year, standard);
+        let end = self.end.instant(year, standard + self.save);
+        if start < end {
+            (start..end).contains(&unix)
+        } else {
+            // southern hemisphere, DST spans the turn of the year
+            unix >= start || unix < end
+        }
+    }
+}
+
+const HOUR: i32 = 3600;
+
+/// EU: last Sunday of March to last Sunday of October, 01:00 UTC
+pub static EU: DstRule = DstRule {
+    start: Transition {
+        month: 3,
+        week: 5,
+        weekday: Weekday::Sunday,
+        time: HOUR,
+        utc: true,
+    },
+    end: Transition {
+        month: 10,
+        week: 5,
+        weekday: Weekday::Sunday,
+        time: HOUR,
+        utc: true,
+    },
+    save: HOUR,
+};
+
+/// US: second Sunday of March to first Sunday of November, 02:00 local
+pub static US: DstRule = DstRule {
+    start: Transition {
+        month: 3,
+        week: 2,
+        weekday: Weekday::Sunday,
+        time: 2 * HOUR,
+        utc: false,
+    },
+    end: Transition {
+        month: 11,
+        week: 1,
+        weekday: Weekday::Sunday,
+        time: 2 * HOUR,
+        utc: false,
+    },
+    save: HOUR,
+};
+
+/// South-east Australia: first Sunday of October to first Sunday
+/// of April, 02:00 standard and 03:00 daylight time
+pub static AU: DstRule = DstRule {
+    start: Transition {
+        month: 10,
+        week: 1,
+        weekday: Weekday::Sunday,
+        time: 2 * HOUR,
+        utc: false,
+    },
+    end: Transition {
+        month: 4,
+        week: 1,
+        weekday: Weekday::Sunday,
+        time: 3 * HOUR,
+        utc: false,
+    },
+    save: HOUR,
+};
+
+/// A named zone with a standard offset and optional DST
+#[derive(Debug, PartialEq, Eq)]
+pub struct Zone {
+    pub name: &'static str,
+    standard: i32,
+    abbreviations: (&'static str, &'static str), // standard, daylight
+    dst: Option<&'static DstRule>,
+}
+
+impl Zone {
+    const fn new(
+        name: &'static str,
+        standard: i32,
+        abbreviations: (&'static str, &'static str),
+        dst: Option<&'static DstRule>,
+    ) -> Self {
+        Self {
+            name,
+            standard,
+            abbreviations,
+            dst,
+        }
+    }
+}
+
+/// The built in zones, named as in the tz database
+pub static ZONES: [Zone; 12] = [
+    Zone::new("Europe/London", 0, ("GMT", "BST"), Some(&EU)),
+    Zone::new("Europe/Berlin", HOUR, ("CET", "CEST"), Some(&EU)),
+    Zone::new("Europe/Helsinki", 2 * HOUR, ("EET", "EEST"), Some(&EU)),
+    Zone::new("America/New_York", -5 * HOUR, ("EST", "EDT"), Some(&US)),
+    Zone::new("America/Chicago", -6 * HOUR, ("CST", "CDT"), Some(&US)),
+    Zone::new("America/Denver", -7 * HOUR, ("MST", "MDT"), Some(&US)),
+    Zone::new("America/Phoenix", -7 * HOUR, ("MST", "MST"), None),
+    Zone::new("America/Los_Angeles", -8 * HOUR, ("PST", "PDT"), Some(&US)),
+    Zone::new("Asia/Kolkata", 5 * HOUR + HOUR / 2, ("IST", "IST"), None),
+    Zone::new("Asia/Shanghai", 8 * HOUR, ("CST", "CST"), None),
+    Zone::new("Asia/Tokyo", 9 * HOUR, ("JST", "JST"), None),
+    Zone::new("Australia/Sydney", 10 * HOUR, ("AEST", "AEDT"), Some(&AU)),
+];
+
+/// How local time relates to UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeZone {
+    Fixed(UtcOffset),
+    Zone(&'static Zone),
+}
+
+impl TimeZone {
+    pub const UTC: TimeZone = TimeZone::Fixed(UtcOffset::UTC);
+
+    /// The offset and abbreviation in effect at `utc`, the
+    /// abbreviation is empty for fixed offsets
+    fn at(&self, utc: DateTime) -> (UtcOffset, &'static str) {
+        match self {
+            TimeZone::Fixed(offset) => (*offset, ""),
+            TimeZone::Zone(zone) => {
+                let daylight = zone
+                    .dst
+                    .is_some_and(|rule| rule.in_effect(utc.to_unix(), utc.year, zone.standard));
+                if daylight {
+                    let save = zone.dst.map_or(0, |rule| rule.save);
+                    (
+                        UtcOffset {
+                            seconds: zone.standard + save,
+                        },
+                        zone.abbreviations.1,
+                    )
+                } else {
+                    (
+                        UtcOffset {
+                            seconds: zone.standard,
+                        },
+                        zone.abbreviations.0,
+                    )
+                }
+            }
+        }
+    }
+
+    pub fn offset_at(&self, utc: DateTime) -> UtcOffset {
+        self.at(utc).0
+    }
+
+    /// Whether daylight saving time is in effect at `utc`
+    pub fn is_dst(&self, utc: DateTime) -> bool {
+        match self {
+            TimeZone::Fixed(_) => false,
+            TimeZone::Zone(zone) => self.offset_at(utc).seconds != zone.standard,
+        }
+    }
+
+    pub fn to_local(&self, utc: DateTime) -> LocalTime {
+        let (offset, abbreviation) = self.at(utc);
+        LocalTime {
+            time: utc
+                .checked_add_seconds(offset.seconds as i64)
+                .unwrap_or(utc),
+            offset,
+            abbreviation,
+        }
+    }
+
+    /// The UTC time at which the clocks in this zone show `local`
+    ///
+    /// A time repeated when DST ends is taken as the first of the
+    /// two, one skipped when DST starts as standard time.
+    pub fn to_utc(&self, local: DateTime) -> DateTime {
+        let (standard, daylight) = match self {
+            TimeZone::Fixed(offset) => (offset.seconds, offset.seconds),
+            TimeZone::Zone(zone) => (
+                zone.standard,
+                zone.standard + zone.dst.map_or(0, |rule| rule.save),
+            ),
+        };
+
+        let shift = |offset: i32| local.checked_add_seconds(-offset as i64);
+        [daylight, standard]
+            .into_iter()
+            .find_map(|offset| shift(offset).filter(|utc| self.offset_at(*utc).seconds == offset))
+            .or_else(|| shift(standard))
+            .unwrap_or(local)
+    }
+}
+
+/// Zone name or offset
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeZone::Fixed(UtcOffset::UTC) => f.write_str("UTC"),
+            TimeZone::Fixed(offset) => write!(f, "{offset}"),
+            TimeZone::Zone(zone) => f.write_str(zone.name),
+        }
+    }
+}
+
+/// A zone name from `ZONES`, ignoring case, or a `UtcOffset`
+impl FromStr for TimeZone {
+    type Err = InvalidTimeZone;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match ZONES.iter().find(|zone| zone.name.eq_ignore_ascii_case(s)) {
+            Some(zone) => Ok(TimeZone::Zone(zone)),
+            None => s.parse().map(TimeZone::Fixed),
+        }
+    }
+}
+
+/// A date and time on the wall clock of some zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub time: DateTime,
+    pub offset: UtcOffset,
+    pub abbreviation: &'static str, // empty for fixed offsets
+}
+
+impl LocalTime {
+    /// Like `DateTime::format`, with `%z` and `%Z` describing this
+    /// zone and `%s` still counting from the epoch in UTC
+    pub fn format<'a>(&self, pattern: &'a str) -> Formatted<'a> {
+        self.time
+            .format(pattern)
+            .in_zone(self.offset, self.abbreviation)
+    }
+
+    pub fn to_utc(&self) -> DateTime {
+        self.time
+            .checked_add_seconds(-self.offset.seconds as i64)
+            .unwrap_or(self.time)
+    }
+}
+
+/// ISO 8601 with the offset, `2025-07-01T14:00:00+02:00`
+impl fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.time.format("%FT%T"), self.offset)
+    }
+}
+
+static SYSTEM: Mutex<TimeZone> = Mutex::new(TimeZone::UTC);
+
+// whether the RTC keeps local time like on most PCs that also run Windows
+static RTC_LOCAL: AtomicBool = AtomicBool::new(false);
+
+/// The zone local times are shown in
+pub fn system() -> TimeZone {
+    without_interrupts(|| *SYSTEM.lock())
+}
+
+pub fn set_system(zone: TimeZone) {
+    without_interrupts(|| *SYSTEM.lock() = zone);
+}
+
+pub fn rtc_is_local() -> bool {
+    RTC_LOCAL.load(Ordering::Relaxed)
+}
+
+/// Whether the RTC holds local time in the system zone instead of UTC
+///
+/// Only changes how the RTC is read and written, not its contents.
+pub fn set_rtc_local(local: bool) {
+    RTC_LOCAL.store(local, Ordering::Relaxed);
+}
+
+pub(crate) fn rtc_to_utc(rtc_time: DateTime) -> DateTime {
+    if rtc_is_local() {
+        system().to_utc(rtc_time)
+    } else {
+        rtc_time
+    }
+}
+
+pub(crate) fn utc_to_rtc(utc: DateTime) -> DateTime {
+    if rtc_is_local() {
+        system().to_local(utc).time
+    } else {
+        utc
+    }
+}
+
+/// Parse ISO 8601 like `DateTime::from_str`, a time without an
+/// offset is local to the system zone
+pub fn parse_local(s: &str) -> Result<DateTime, ParseDateTimeError> {
+    match parse_iso(s)? {
+        (time, Some(offset)) => time
+            .checked_add_seconds(-offset.seconds as i64)
+            .ok_or(ParseDateTimeError),
+        (time, None) => Ok(system().to_utc(time)),
+    }
+}
+
+/// Apply `tz=<zone or offset>` and `rtc=local|utc` from the command line
+pub fn init() {
+    if let Some(name) = cmdline::get("tz") {
+        match name.parse() {
+            Ok(zone) => set_system(zone),
+            Err(InvalidTimeZone) => println!("unknown time zone {name}, using UTC"),
+        }
+    }
+
+    match cmdline::get("rtc") {
+        Some("local") => set_rtc_local(true),
+        Some("utc") | None => set_rtc_local(false),
+        Some(other) => println!("rtc={other} is neither local nor utc, assuming utc"),
+    }
+}
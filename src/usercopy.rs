@@ -0,0 +1,265 @@
+use core::arch::{asm, global_asm};
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use crate::{cpu, mem};
+
+/// First address past the lower (user) half of the address space
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// read by the int 0x80 entry stub, which must not clac without SMAP
+pub(crate) static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// The range is null, overflows or is not mapped for user access
+    BadAddress,
+    /// The range passed validation but faulted anyway, e.g. because
+    /// it was unmapped in the meantime
+    Fault,
+}
+
+// An exception table entry pairs an instruction that may fault on a
+// user address with the address to resume at if it does. Both fields
+// are offsets relative to the field itself so the table needs no
+// relocations. The linker collects all entries between
+// __ex_table_start and __ex_table_end.
+#[repr(C)]
+struct ExceptionEntry {
+    insn: i32,
+    fixup: i32,
+}
+
+extern "C" {
+    static __ex_table_start: ExceptionEntry;
+    static __ex_table_end: ExceptionEntry;
+
+    /// Copy `len` bytes with `rep movsb`, returns the number of bytes
+    /// left uncopied (0 on success).
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+// rdi = dst, rsi = src, rdx = len. On a fault inside rep movsb
+// rcx still holds the number of bytes not yet copied.
+global_asm!(
+    ".section .text.copy_user_bytes, \"ax\"",
+    ".global copy_user_bytes",
+    "copy_user_bytes:",
+    "    mov rcx, rdx",
+    "copy_user_bytes_insn:",
+    "    rep movsb",
+    "    xor eax, eax",
+    "    ret",
+    "copy_user_bytes_fixup:",
+    "    mov rax, rcx",
+    "    ret",
+    ".pushsection .ex_table, \"a\"",
+    "    .balign 4",
+    "    .long copy_user_bytes_insn - .",
+    "    .long copy_user_bytes_fixup - .",
+    ".popsection",
+);
+
+/// Enable SMEP and SMAP if the CPU supports them
+///
+/// With SMEP the kernel faults when executing user pages, with
+/// SMAP it faults when touching user pages outside of the copy
+/// helpers in this module.
+pub fn init() {
+    let smep = cpu::has_smep();
+    let smap = cpu::has_smap();
+
+    unsafe {
+        Cr4::update(|flags| {
+            flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+            flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+        });
+    }
+    SMAP_ENABLED.store(smap, Ordering::Relaxed);
+
+    crate::println!("SMEP: {}, SMAP: {}", smep, smap);
+}
+
+/// Look up `rip` in the exception table
+///
+/// Returns the address to resume execution at when the
+/// faulting instruction is one of the user copy routines.
+pub fn fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    let mut entry = &raw const __ex_table_start;
+    let end = &raw const __ex_table_end;
+
+    while entry < end {
+        unsafe {
+            let insn_field = &raw const (*entry).insn;
+            let fixup_field = &raw const (*entry).fixup;
+            let insn = (insn_field as u64).wrapping_add_signed((*entry).insn as i64);
+
+            if insn == rip.as_u64() {
+                let fixup = (fixup_field as u64).wrapping_add_signed((*entry).fixup as i64);
+                return Some(VirtAddr::new(fixup));
+            }
+
+            entry = entry.add(1);
+        }
+    }
+
+    None
+}
+
+/// Temporarily allow supervisor access to user pages (stac),
+/// access is revoked again (clac) when dropped.
+struct UserAccess;
+
+impl UserAccess {
+    fn begin() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        UserAccess
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Run the fault-tolerant copy with user access enabled
+fn copy_bytes(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UserCopyError> {
+    let _access = UserAccess::begin();
+    match unsafe { copy_user_bytes(dst, src, len) } {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Fault),
+    }
+}
+
+/// Check that user mode may read all of `[addr, addr + len)`
+///
+/// The kernel image, heap and device windows live in the lower half
+/// as well, so every page is looked up in the page tables.
+pub fn validate_range(addr: u64, len: usize) -> Result<(), UserCopyError> {
+    check_pages(addr, len, false)
+}
+
+fn check_pages(addr: u64, len: usize, write: bool) -> Result<(), UserCopyError> {
+    if addr == 0 {
+        return Err(UserCopyError::BadAddress);
+    }
+    let end = match addr.checked_add(len as u64) {
+        Some(end) if end <= USER_END => end,
+        _ => return Err(UserCopyError::BadAddress),
+    };
+    if len == 0 {
+        return Ok(());
+    }
+
+    let pages = Page::range_inclusive(
+        Page::containing_address(VirtAddr::new(addr)),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    for page in pages {
+        if !mem::user_page_accessible(page, write) {
+            return Err(UserCopyError::BadAddress);
+        }
+    }
+    Ok(())
+}
+
+/// Copy `dst.len()` bytes from user address `src` into `dst`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UserCopyError> {
+    check_pages(src, dst.len(), false)?;
+    copy_bytes(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copy `src` to user address `dst`, which must be writable
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UserCopyError> {
+    check_pages(dst, src.len(), true)?;
+    copy_bytes(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Copy from any address, kernel or user, without faulting
+///
+/// Meant for diagnostics such as dumping the bytes at a faulting
+/// instruction pointer, where the address may be garbage.
+pub fn read_nofault(dst: &mut [u8], src: u64) -> Result<(), UserCopyError> {
+    copy_bytes(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Plain data that is valid for any bit pattern
+///
+/// # Safety
+///
+/// Implementors must have no padding, no references or pointers
+/// the kernel might follow and no invalid bit patterns, such as
+/// integers and arrays of them.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for isize {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A pointer into user memory
+///
+/// The kernel never dereferences it directly, all access goes
+/// through `read`/`write` which validate the range and recover
+/// from faults.
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Pod> UserPtr<T> {
+    pub const fn new(addr: u64) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn read(&self) -> Result<T, UserCopyError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        check_pages(self.addr, size_of::<T>(), false)?;
+        copy_bytes(
+            value.as_mut_ptr() as *mut u8,
+            self.addr as *const u8,
+            size_of::<T>(),
+        )?;
+        // every byte was written and any bit pattern is a valid T
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: &T) -> Result<(), UserCopyError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+}
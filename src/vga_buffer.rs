@@ -5,7 +5,12 @@ use volatile::Volatile;
 use x86_64::instructions::interrupts::without_interrupts;
 
 const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+
+// the top row is the status bar, text scrolls in the rows below it
+const STATUS_ROW: usize = 0;
+const TEXT_TOP: usize = 1;
+const STATUS_COLOR: ColorDesc = ColorDesc::new(Color::Black, Color::LightGray);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -97,10 +102,98 @@ impl Writer {
         }
     }
 
+    /// Erase the character before the cursor, at a row start the
+    /// last one of the previous row since `write_byte` wrapped there
+    pub fn backspace(&mut self) {
+        if self.column_pos > 0 {
+            self.column_pos -= 1;
+        } else if self.row_pos > TEXT_TOP {
+            self.row_pos -= 1;
+            self.column_pos = BUFFER_WIDTH - 1;
+        } else {
+            return;
+        }
+
+        let color = self.color_desc;
+        self.buff.chars[self.row_pos][self.column_pos].write(Char {
+            character: b' ',
+            color_desc: color,
+        });
+    }
+
     pub fn new_line(&mut self) {
-        self.row_pos += 1;
+        if self.row_pos + 1 < BUFFER_HEIGHT {
+            self.row_pos += 1;
+        } else {
+            self.scroll();
+        }
         self.column_pos = 0;
     }
+
+    /// Move the text rows up by one, leaving the status bar alone
+    fn scroll(&mut self) {
+        for row in TEXT_TOP + 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.buff.chars[row][col].read();
+                self.buff.chars[row - 1][col].write(character);
+            }
+        }
+        self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let blank = Char {
+            character: b' ',
+            color_desc: self.color_desc,
+        };
+        for col in 0..BUFFER_WIDTH {
+            self.buff.chars[row][col].write(blank);
+        }
+    }
+
+    /// Replace the status bar with `args`, cut off at the screen width
+    pub fn write_status(&mut self, args: fmt::Arguments) {
+        use core::fmt::Write;
+
+        let mut status = StatusWriter {
+            row: &mut self.buff.chars[STATUS_ROW],
+            column: 0,
+        };
+        let _ = status.write_fmt(args);
+        while status.column < BUFFER_WIDTH {
+            status.put(b' ');
+        }
+    }
+}
+
+/// Writes into the status bar row
+struct StatusWriter<'a> {
+    row: &'a mut [Volatile<Char>; BUFFER_WIDTH],
+    column: usize,
+}
+
+impl StatusWriter<'_> {
+    fn put(&mut self, byte: u8) {
+        if self.column < BUFFER_WIDTH {
+            self.row[self.column].write(Char {
+                character: byte,
+                color_desc: STATUS_COLOR,
+            });
+            self.column += 1;
+        }
+    }
+}
+
+impl fmt::Write for StatusWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                0x20..=0x7e => self.put(byte),
+                _ => self.put(0xfe),
+            }
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Write for Writer {
@@ -112,7 +205,7 @@ impl fmt::Write for Writer {
 
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        row_pos: 0,
+        row_pos: TEXT_TOP,
         column_pos: 0,
         color_desc: ColorDesc::new(Color::Yellow, Color::Black),
         buff: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -128,8 +221,33 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+/// Like `print!`, but drops the output instead of spinning when
+/// the writer is busy, for handlers that may interrupt its holder
+///
+/// Returns whether anything was printed.
+pub fn try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+
+    without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => writer.write_fmt(args).is_ok(),
+        None => false,
+    })
+}
+
+pub fn backspace() {
+    without_interrupts(|| WRITER.lock().backspace());
+}
+
+/// Show `args` in the status bar, used as `set_status(format_args!(..))`
+///
+/// Takes the same lock as `print!`, so it never lands in the middle
+/// of other output and may be called from interrupt handlers.
+pub fn set_status(args: fmt::Arguments) {
+    without_interrupts(|| WRITER.lock().write_status(args));
+}
+
 pub fn set_print_color(color: ColorDesc) {
-    WRITER.lock().color_desc = color;
+    without_interrupts(|| WRITER.lock().color_desc = color);
 }
 
 #[macro_export]
@@ -0,0 +1,159 @@
+use core::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::clock::Instant;
+use crate::time::DateTime;
+use crate::{println, rtc, timer};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// How often the clock is compared against the RTC
+const RESYNC_PERIOD: Duration = Duration::from_secs(64);
+
+/// Errors of at least this many nanoseconds are stepped, smaller
+/// ones are slewed away
+const STEP_THRESHOLD: i64 = NANOS_PER_SEC;
+
+/// Fastest rate at which an error is slewed away, in parts per million
+const MAX_SLEW_PPM: i64 = 500;
+
+/// The RTC update cycle takes 1984 us, the seconds advance when it
+/// starts and the update-ended interrupt comes when it is over
+const UPDATE_CYCLE: i64 = 1_984_000;
+
+/// The wall clock as a linear function of the monotonic clock
+#[derive(Debug, Clone, Copy)]
+struct State {
+    base: Instant, // monotonic time of the last adjustment
+    base_ns: u64,  // wall time at `base`, nanoseconds since the Unix epoch
+    slew_ppm: i64, // rate correction until `slew_end`, positive runs fast
+    slew_end: Instant,
+}
+
+impl State {
+    fn starting_at(now: Instant, wall_ns: u64) -> Self {
+        Self {
+            base: now,
+            base_ns: wall_ns,
+            slew_ppm: 0,
+            slew_end: now,
+        }
+    }
+
+    /// Wall time at monotonic time `now`, in nanoseconds
+    fn at(&self, now: Instant) -> u64 {
+        let elapsed = now.duration_since(self.base).as_nanos() as u64;
+        let slewed = self.slew_end.duration_since(self.base).as_nanos() as u64;
+        let correction = slewed.min(elapsed) as i64 * self.slew_ppm / 1_000_000;
+        (self.base_ns + elapsed).saturating_add_signed(correction)
+    }
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+// last measured difference from the RTC, positive when the clock was behind
+static LAST_ERROR: AtomicI64 = AtomicI64::new(0);
+static STEPS: AtomicU64 = AtomicU64::new(0);
+
+/// Start the clock from the RTC and keep it in step with it
+///
+/// Needs the monotonic clock, the timer and the RTC interrupt.
+pub fn init() {
+    let rtc_time = DateTime::read_rtc();
+    // the RTC is somewhere within this second, assume the middle
+    let wall_ns = unix_nanos(rtc_time) + NANOS_PER_SEC as u64 / 2;
+    without_interrupts(|| *STATE.lock() = Some(State::starting_at(Instant::now(), wall_ns)));
+
+    // find the second boundary, then step onto it
+    let precise = rtc::on_next_update(|| correct(DateTime::read_rtc(), true, true));
+    if !precise {
+        println!("wall clock: no RTC update interrupt, syncing to the second only");
+    }
+    timer::every(RESYNC_PERIOD, resync);
+}
+
+/// Time since the Unix epoch, `None` before `init`
+pub fn since_epoch() -> Option<Duration> {
+    let now = Instant::now();
+    let state = without_interrupts(|| *STATE.lock())?;
+    Some(Duration::from_nanos(state.at(now)))
+}
+
+/// Step the clock to `time`, after the RTC was set to it
+pub fn set(time: DateTime) {
+    let state = State::starting_at(Instant::now(), unix_nanos(time));
+    without_interrupts(|| {
+        let mut current = STATE.lock();
+        if current.is_some() {
+            *current = Some(state);
+        }
+    });
+}
+
+/// Nanoseconds the clock was behind the RTC at the last resync,
+/// negative when it was ahead
+pub fn last_error_ns() -> i64 {
+    LAST_ERROR.load(Ordering::Relaxed)
+}
+
+/// Times the clock jumped to catch up with the RTC
+pub fn steps() -> u64 {
+    STEPS.load(Ordering::Relaxed)
+}
+
+fn unix_nanos(time: DateTime) -> u64 {
+    time.to_unix().max(0) as u64 * NANOS_PER_SEC as u64
+}
+
+/// Compare against the RTC, on the next second boundary if the
+/// update interrupt is available
+fn resync() {
+    if !rtc::on_next_update(|| correct(DateTime::read_rtc(), true, false)) {
+        correct(DateTime::read_rtc(), false, false);
+    }
+}
+
+/// Steer the clock towards `rtc_time`
+///
+/// With `precise` the RTC has just advanced to `rtc_time`, otherwise
+/// the RTC is anywhere within that second and only a clock outside of
+/// it is corrected. Small errors are slewed unless `step` is set.
+fn correct(rtc_time: DateTime, precise: bool, step: bool) {
+    let now = Instant::now();
+    let rtc_ns = unix_nanos(rtc_time) as i64;
+
+    without_interrupts(|| {
+        let mut state = STATE.lock();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        let wall = state.at(now) as i64;
+        let error = if precise {
+            rtc_ns + UPDATE_CYCLE - wall
+        } else if wall < rtc_ns {
+            rtc_ns - wall
+        } else if wall >= rtc_ns + NANOS_PER_SEC {
+            rtc_ns + NANOS_PER_SEC - wall
+        } else {
+            0
+        };
+        LAST_ERROR.store(error, Ordering::Relaxed);
+
+        if step || error.abs() >= STEP_THRESHOLD {
+            *state = State::starting_at(now, (wall + error).max(0) as u64);
+            STEPS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            // run at most MAX_SLEW_PPM fast or slow until the error is gone
+            let slew_ns = error.unsigned_abs() * 1_000_000 / MAX_SLEW_PPM as u64;
+            *state = State {
+                base: now,
+                base_ns: wall as u64,
+                slew_ppm: error.signum() * MAX_SLEW_PPM,
+                slew_end: now + Duration::from_nanos(slew_ns),
+            };
+        }
+    });
+}